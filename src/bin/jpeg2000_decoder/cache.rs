@@ -0,0 +1,477 @@
+//! # cache.rs  -- disk cache of fetched JPEG 2000 bytes.
+//
+//  Animats
+//  March, 2023
+//
+//  Each cache file holds the first bytes of one asset, as many as have been
+//  fetched so far. "KEY.part" is a prefix, "KEY.full" is the whole asset.
+//  The key is the texture UUID, if the URL has one, so the same texture
+//  from different servers is fetched once. Otherwise it is a hash of the URL.
+//
+//  Files are written to a temporary name and renamed, so a crash never
+//  leaves a damaged cache file. Several decoder processes can share one cache.
+//  Modification time is last use. When the cache is over its size limit,
+//  the least recently used files are deleted.
+//
+//  Each process keeps a running total of the cache size, from one scan of the
+//  directory at start and the files it writes since. The directory is scanned again
+//  only when that total is over the limit, or after a while, to count what
+//  other processes sharing the cache have written.
+//
+//  Textures with a UUID never change. Other URLs can. For those, each file
+//  starts with the server's ETag and Last-Modified, and a copy not checked
+//  recently is checked with a conditional request. A 304 reply means the
+//  copy is current, and only the time it was checked changes.
+//
+use crate::fetch::{fetch_asset, fetch_asset_conditional, AssetPart, Condition, RetryPolicy, Validators};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use ureq::Agent;
+
+/// Suffix of a cache file holding the first part of an asset.
+const PART_SUFFIX: &str = "part";
+/// Suffix of a cache file holding a whole asset.
+const FULL_SUFFIX: &str = "full";
+/// Suffix of a file being written.
+const TMP_SUFFIX: &str = "tmp";
+/// First line of a cache file. Files without it are not used.
+const MAGIC: &str = "jpeg2000-decoder cache 1";
+/// A copy of an asset which can change is checked with the server if not checked for this long.
+const REVALIDATE_AFTER: Duration = Duration::from_secs(60);
+/// A write scans the directory if it has not been scanned for this long, to count files written by other processes.
+const SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Bytes of an asset held in the cache.
+#[derive(Debug, Default, PartialEq)]
+pub struct CacheEntry {
+    /// The first bytes of the asset.
+    pub data: Vec<u8>,
+    /// True if this is the whole asset.
+    pub complete: bool,
+    /// Identifies the version of the asset, if the server said.
+    pub validators: Validators,
+    /// When the server last sent or confirmed this version, seconds since the Unix epoch.
+    pub validated: u64,
+}
+
+impl CacheEntry {
+    /// As stored in a file. Header lines, like HTTP headers, a blank line, then the bytes.
+    fn encode(&self) -> Vec<u8> {
+        let mut header = format!("{}\nvalidated: {}\n", MAGIC, self.validated);
+        if let Some(etag) = &self.validators.etag {
+            header += &format!("etag: {}\n", etag);
+        }
+        if let Some(last_modified) = &self.validators.last_modified {
+            header += &format!("last-modified: {}\n", last_modified);
+        }
+        header.push('\n');
+        let mut bytes = header.into_bytes();
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// From a file, or None if not a cache file.
+    fn decode(mut bytes: Vec<u8>, complete: bool) -> Option<CacheEntry> {
+        let header_end = bytes.windows(2).position(|w| w == b"\n\n")? + 2;
+        let header = std::str::from_utf8(&bytes[..header_end]).ok()?;
+        let mut lines = header.lines();
+        if lines.next()? != MAGIC {
+            return None;
+        }
+        let mut entry = CacheEntry {
+            complete,
+            ..Default::default()
+        };
+        for line in lines.filter(|line| !line.is_empty()) {
+            match line.split_once(": ")? {
+                ("validated", v) => entry.validated = v.parse().ok()?,
+                ("etag", v) => entry.validators.etag = Some(v.to_string()),
+                ("last-modified", v) => entry.validators.last_modified = Some(v.to_string()),
+                _ => {} // for later versions
+            }
+        }
+        bytes.drain(..header_end);
+        entry.data = bytes;
+        Some(entry)
+    }
+}
+
+/// Now, in seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs())
+}
+
+/// Disk cache of fetched bytes.
+pub struct DiskCache {
+    /// Cache directory
+    dir: PathBuf,
+    /// Size limit for all the files, bytes.
+    max_bytes: u64,
+    /// For unique temporary file names.
+    tmp_counter: AtomicU64,
+    /// Bytes in the cache, as far as this process knows.
+    size: Mutex<CacheSize>,
+}
+
+/// Running total of the cache size.
+struct CacheSize {
+    /// Bytes in cache files, as of the last scan, plus those written since.
+    bytes: u64,
+    /// Time of the last scan of the directory.
+    scanned: Instant,
+}
+
+/// Texture UUID, lower case, if the URL has one.
+/// Second Life and Open Simulator texture URLs end in "?texture_id=UUID".
+/// Textures never change, so a cached copy of a texture is always current.
+pub fn texture_id(url: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    query.split('&').filter_map(|param| param.strip_prefix("texture_id=")).find_map(|id| {
+        let is_uuid = id.len() == 36
+            && id.char_indices().all(|(i, c)| if matches!(i, 8 | 13 | 18 | 23) { c == '-' } else { c.is_ascii_hexdigit() });
+        is_uuid.then(|| id.to_ascii_lowercase())
+    })
+}
+
+impl DiskCache {
+    /// Use this directory for the cache, creating it if necessary.
+    pub fn new(dir: &Path, max_bytes: u64) -> Result<DiskCache, std::io::Error> {
+        std::fs::create_dir_all(dir)?;
+        let cache = DiskCache {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            tmp_counter: AtomicU64::new(0),
+            size: Mutex::new(CacheSize {
+                bytes: 0,
+                scanned: Instant::now(),
+            }),
+        };
+        let bytes = cache.evict()?; // initial size
+        cache.size.lock().unwrap().bytes = bytes;
+        Ok(cache)
+    }
+
+    /// Cache key for a URL. The texture UUID if there is one, otherwise a hash of the URL.
+    fn key(url: &str) -> String {
+        texture_id(url).unwrap_or_else(|| format!("{:016x}", fnv1a_hash(url.as_bytes())))
+    }
+
+    /// Path of a cache file.
+    fn path(&self, key: &str, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, suffix))
+    }
+
+    /// What the cache holds for this URL, if anything. Marks it as recently used,
+    /// if the cache can be written.
+    pub fn get(&self, url: &str) -> Option<CacheEntry> {
+        let key = DiskCache::key(url);
+        for (suffix, complete) in [(FULL_SUFFIX, true), (PART_SUFFIX, false)] {
+            let path = self.path(&key, suffix);
+            if let Ok(mut file) = File::open(&path) {
+                let mut bytes = Vec::new();
+                if file.read_to_end(&mut bytes).is_ok() {
+                    //  Last use. A read-only cache still works, without it.
+                    let _ = File::options().write(true).open(&path).and_then(|file| file.set_modified(SystemTime::now()));
+                    return CacheEntry::decode(bytes, complete);
+                }
+            }
+        }
+        None
+    }
+
+    /// Size of the cache files for a key, if any.
+    fn held_len(&self, key: &str) -> u64 {
+        [FULL_SUFFIX, PART_SUFFIX]
+            .iter()
+            .filter_map(|suffix| std::fs::metadata(self.path(key, suffix)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+
+    /// Store the first bytes of an asset, replacing whatever was held for it.
+    pub fn put(&self, url: &str, entry: &CacheEntry) -> Result<(), std::io::Error> {
+        let key = DiskCache::key(url);
+        let tmp_path = self.path(
+            &key,
+            &format!("{}.{}.{}", std::process::id(), self.tmp_counter.fetch_add(1, Ordering::Relaxed), TMP_SUFFIX),
+        );
+        let (suffix, other_suffix) = if entry.complete { (FULL_SUFFIX, PART_SUFFIX) } else { (PART_SUFFIX, FULL_SUFFIX) };
+        let encoded = entry.encode();
+        let old_len = self.held_len(&key);
+        let status = File::create(&tmp_path)
+            .and_then(|mut file| file.write_all(&encoded).and_then(|_| file.sync_all()))
+            .and_then(|_| std::fs::rename(&tmp_path, self.path(&key, suffix)));
+        if status.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+            return status;
+        }
+        let _ = std::fs::remove_file(self.path(&key, other_suffix)); // only one file per key
+        let mut size = self.size.lock().unwrap();
+        size.bytes = size.bytes.saturating_sub(old_len) + encoded.len() as u64;
+        if size.bytes > self.max_bytes || size.scanned.elapsed() >= SCAN_INTERVAL {
+            size.bytes = self.evict()?;
+            size.scanned = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Forget an asset, because the copy held is out of date.
+    pub fn remove(&self, url: &str) {
+        let key = DiskCache::key(url);
+        let old_len = self.held_len(&key);
+        for suffix in [FULL_SUFFIX, PART_SUFFIX] {
+            let _ = std::fs::remove_file(self.path(&key, suffix)); // may not exist
+        }
+        let mut size = self.size.lock().unwrap();
+        size.bytes = size.bytes.saturating_sub(old_len);
+    }
+
+    /// Delete least recently used files until the cache is under its size limit.
+    /// Also deletes temporary files left by crashes.
+    /// Returns the bytes left in cache files. Scans the whole directory.
+    fn evict(&self) -> Result<u64, std::io::Error> {
+        /// Temporary files older than this were left by a crash.
+        const TMP_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(3600);
+        let now = SystemTime::now();
+        let mut files = Vec::new();
+        let mut total: u64 = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue, // deleted by another process, or not ours
+            };
+            let modified = metadata.modified()?;
+            let path = entry.path();
+            match path.extension().and_then(|s| s.to_str()) {
+                Some(PART_SUFFIX) | Some(FULL_SUFFIX) => {
+                    total += metadata.len();
+                    files.push((modified, metadata.len(), path));
+                }
+                Some(TMP_SUFFIX) if now.duration_since(modified).is_ok_and(|age| age > TMP_MAX_AGE) => {
+                    let _ = std::fs::remove_file(&path);
+                }
+                _ => {}
+            }
+        }
+        if total <= self.max_bytes {
+            return Ok(total);
+        }
+        files.sort_by_key(|(modified, _, _)| *modified); // oldest first
+        for (_, len, path) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            log::debug!(step = "evict", bytes = len; "Cache evicted {:?}", path);
+            let _ = std::fs::remove_file(&path); // may already be gone
+            total = total.saturating_sub(len);
+        }
+        Ok(total)
+    }
+}
+
+/// 64-bit FNV-1a hash.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, b| (hash ^ u64::from(*b)).wrapping_mul(PRIME))
+}
+
+/// Fetch asset through the cache.
+/// Bytes held in the cache are not fetched again. Anything beyond them is fetched
+/// with a Range request, and added to the cache.
+/// Copies of assets which can change are checked with the server first, if not checked recently.
+/// Cache errors are logged, and the fetch goes ahead without the cache.
+pub fn fetch_asset_cached(
+    agent: &Agent,
+    retry_policy: &RetryPolicy,
+    cache: &DiskCache,
+    url: &str,
+    byte_range_opt: Option<(u32, u32)>,
+) -> Result<AssetPart, ureq::Error> {
+    let (first, last) = byte_range_opt.unwrap_or((0, u32::MAX));
+    let mut held = cache.get(url).unwrap_or_default();
+    let held_len = held.data.len() as u64;
+    //  Only copies which can change, and which the server gave us a way to check, are checked.
+    let can_change = !held.data.is_empty() && texture_id(url).is_none() && !held.validators.is_empty();
+    if held.complete || held_len > u64::from(last) {
+        let stale = can_change && unix_time().saturating_sub(held.validated) >= REVALIDATE_AFTER.as_secs();
+        if stale {
+            let part = fetch_asset_conditional(agent, retry_policy, url, byte_range_opt, Condition::IfModified(&held.validators))?;
+            if !part.not_modified {
+                //  Changed on the server. What was fetched replaces the copy.
+                log::debug!(step = "cache", url = url, changed = true; "Cached copy of {} is out of date", url);
+                return Ok(store(cache, url, Vec::new(), part, first, last));
+            }
+            held.validators = part.validators.or(&held.validators);
+            held.validated = unix_time();
+            if let Err(e) = cache.put(url, &held) {
+                log::warn!(step = "cache", url = url; "Unable to write cache for {}: {}", url, e);
+            }
+        }
+        log::debug!(step = "cache", url = url, hit = true, bytes = held.data.len(), checked = stale; "Cache hit for {}", url);
+        return Ok(part_of(held.data, held.complete, first, last));
+    }
+    if held_len < u64::from(first) {
+        //  Gap between what we have and what is wanted. Fetch just what is wanted, without caching it.
+        return fetch_asset(agent, retry_policy, url, byte_range_opt);
+    }
+    log::debug!(step = "cache", url = url, hit = false, bytes = held.data.len(); "Cache miss for {}, have {} bytes", url, held_len);
+    //  Fetch the rest, after what the cache holds. If the asset has changed, the server sends all of it.
+    let fetch_range = if held_len == 0 && byte_range_opt.is_none() { None } else { Some((held_len as u32, last)) };
+    let condition = if can_change { Condition::IfUnchanged(&held.validators) } else { Condition::None };
+    let part = fetch_asset_conditional(agent, retry_policy, url, fetch_range, condition)?;
+    let part = AssetPart {
+        validators: part.validators.clone().or(&held.validators),
+        ..part
+    };
+    Ok(store(cache, url, held.data, part, first, last))
+}
+
+/// Add a fetched part to the bytes held, store that in the cache, and return the range first..=last.
+/// A part starting at 0 replaces the bytes held.
+fn store(cache: &DiskCache, url: &str, held_data: Vec<u8>, part: AssetPart, first: u32, last: u32) -> AssetPart {
+    let data = if part.start == 0 {
+        part.data // server sent from the beginning
+    } else if part.start as usize == held_data.len() {
+        let mut data = held_data;
+        data.extend_from_slice(&part.data);
+        data
+    } else {
+        cache.remove(url); // cannot be added to what is held, which may be out of date
+        return part; // unexpected range from server. Let the caller sort it out.
+    };
+    let entry = CacheEntry {
+        data,
+        complete: part.at_end,
+        validators: part.validators,
+        validated: unix_time(),
+    };
+    if let Err(e) = cache.put(url, &entry) {
+        log::warn!(step = "cache", url = url; "Unable to write cache for {}: {}", url, e);
+    }
+    part_of(entry.data, entry.complete, first, last)
+}
+
+/// The part of the first bytes of an asset in the range first..=last.
+fn part_of(mut data: Vec<u8>, complete: bool, first: u32, last: u32) -> AssetPart {
+    let len = data.len();
+    let end = (u64::from(last) + 1).min(len as u64) as usize;
+    let start = (first as usize).min(end);
+    data.truncate(end);
+    data.drain(..start);
+    AssetPart {
+        data,
+        start: start as u32,
+        at_end: complete && end == len,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_disk_cache() {
+    use crate::fetch::{build_agent, DEFAULT_NETWORK_TIMEOUT, DEFAULT_USER_AGENT};
+    const UUID: &str = "8DCD4A48-2D37-4909-9F78-F7A9EB4EF903";
+    const URL1: &str = "http://asset.example.com/?texture_id=8DCD4A48-2D37-4909-9F78-F7A9EB4EF903";
+    const URL2: &str = "http://other.example.com/cap/1234?texture_id=8dcd4a48-2d37-4909-9f78-f7a9eb4ef903";
+    const URL3: &str = "http://www.example.com/image.j2k";
+    let entry = |fill: u8, len: usize, complete: bool| CacheEntry {
+        data: vec![fill; len],
+        complete,
+        validated: unix_time(),
+        ..Default::default()
+    };
+    //  Keys
+    assert_eq!(DiskCache::key(URL1), UUID.to_ascii_lowercase());
+    assert_eq!(DiskCache::key(URL2), DiskCache::key(URL1)); // same texture, different server
+    assert_eq!(DiskCache::key(URL3).len(), 16);
+    assert_ne!(DiskCache::key(URL3), DiskCache::key("http://www.example.com/image2.j2k"));
+    assert_eq!(DiskCache::key("http://x.com/?texture_id=../../etc").len(), 16); // not a UUID
+    //  File format
+    let validated = CacheEntry {
+        validators: Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        },
+        ..entry(5, 10, true)
+    };
+    assert_eq!(CacheEntry::decode(validated.encode(), true).as_ref(), Some(&validated));
+    assert_eq!(CacheEntry::decode(vec![5; 10], true), None); // no header
+    //  Store and retrieve
+    let dir = std::env::temp_dir().join(format!("jpeg2000-decoder-cache-{}", std::process::id()));
+    let cache = DiskCache::new(&dir, 1100).unwrap();
+    assert_eq!(cache.get(URL1), None);
+    cache.put(URL1, &entry(1, 100, false)).unwrap();
+    assert_eq!(cache.get(URL2), Some(entry(1, 100, false)));
+    cache.put(URL1, &entry(2, 300, true)).unwrap();
+    assert_eq!(cache.get(URL1), Some(entry(2, 300, true)));
+    assert!(!dir.join(format!("{}.{}", DiskCache::key(URL1), PART_SUFFIX)).exists()); // part replaced by full
+    //  Ranges from the cache. No network access, since the cache has the whole asset.
+    let agent = build_agent(DEFAULT_USER_AGENT, 1, DEFAULT_NETWORK_TIMEOUT);
+    let part = fetch_asset_cached(&agent, &RetryPolicy::default(), &cache, URL1, Some((250, 399))).unwrap();
+    assert_eq!((part.start, part.data.len(), part.at_end), (250, 50, true));
+    let part = fetch_asset_cached(&agent, &RetryPolicy::default(), &cache, URL1, Some((0, 99))).unwrap();
+    assert_eq!((part.start, part.data.len(), part.at_end), (0, 100, false));
+    //  Least recently used is evicted first.
+    std::thread::sleep(std::time::Duration::from_millis(20)); // so modification times differ
+    cache.put(URL3, &entry(3, 600, false)).unwrap();
+    assert!(cache.get(URL1).is_some()); // about 1000 bytes with headers, under limit
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    cache.put("http://www.example.com/image2.j2k", &entry(4, 200, true)).unwrap();
+    assert_eq!(cache.get(URL3), None); // oldest use, evicted
+    assert!(cache.get(URL1).is_some());
+    //  Running total matches the files, and a new cache finds the same.
+    let held: u64 = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().metadata().unwrap().len()).sum();
+    assert_eq!(cache.size.lock().unwrap().bytes, held);
+    assert_eq!(DiskCache::new(&dir, 1100).unwrap().size.lock().unwrap().bytes, held);
+    cache.remove(URL1);
+    assert_eq!(cache.get(URL2), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cache_validation() {
+    use crate::fetch::{build_agent, serve_responses, DEFAULT_NETWORK_TIMEOUT, DEFAULT_USER_AGENT};
+    let dir = std::env::temp_dir().join(format!("jpeg2000-decoder-validate-{}", std::process::id()));
+    let cache = DiskCache::new(&dir, 1_000_000).unwrap();
+    let agent = build_agent(DEFAULT_USER_AGENT, 1, DEFAULT_NETWORK_TIMEOUT);
+    let (url, server) = serve_responses(vec![
+        "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n".to_string(),
+        "HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 5\r\nConnection: close\r\n\r\nnewer".to_string(),
+    ]);
+    //  Not checked for a long time, so it is checked. Still current.
+    let old = CacheEntry {
+        data: b"older".to_vec(),
+        complete: true,
+        validators: Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        },
+        validated: 0,
+    };
+    cache.put(&url, &old).unwrap();
+    let part = fetch_asset_cached(&agent, &RetryPolicy::default(), &cache, &url, None).unwrap();
+    assert_eq!(part.data, b"older");
+    let held = cache.get(&url).unwrap();
+    assert!(held.validated > 0); // checked time updated
+    assert_eq!(held.validators, old.validators);
+    //  Checked just now, so no request.
+    assert_eq!(fetch_asset_cached(&agent, &RetryPolicy::default(), &cache, &url, None).unwrap().data, b"older");
+    //  Changed on the server.
+    cache.put(&url, &old).unwrap();
+    let part = fetch_asset_cached(&agent, &RetryPolicy::default(), &cache, &url, None).unwrap();
+    assert_eq!(part.data, b"newer");
+    assert_eq!(cache.get(&url).unwrap().validators.etag.as_deref(), Some("\"v2\""));
+    let requests = server.join().unwrap();
+    assert_eq!(requests.len(), 2);
+    for request in requests {
+        let request = request.to_ascii_lowercase();
+        assert!(request.contains("if-none-match: \"v1\""));
+        assert!(request.contains("if-modified-since: wed, 21 oct 2015 07:28:00 gmt"));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
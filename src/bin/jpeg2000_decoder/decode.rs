@@ -16,9 +16,17 @@
 //! * bpp -- not used, deprecated. Ref: https://github.com/uclouvain/openjpeg/pull/1383
 //! * resno_decoded -- Not clear, should be the number of discard levels available.
 
-use crate::fetch::{build_agent, fetch_asset, err_is_retryable};
+use crate::cache::{fetch_asset_cached, DiskCache};
+use crate::fetch::{fetch_asset, err_is_retryable, AssetPart, RetryPolicy};
+use crate::stats::{record_compression, CompressionStats, COMPRESSION_STATS};
+use image::DynamicImage;
+use jpeg2000_decoder::{bytes_for_discard_level, parse_header, HeaderError, ImageHeader, ProgressionOrder, EOC};
 use jpeg2k::DecodeParameters;
 use std::convert;
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
 /*
 use anyhow::{Error};
 use jpeg2k::*;
@@ -38,6 +46,8 @@ pub enum AssetError {
     Jpeg(jpeg2k::error::Error),
     /// Content errors
     Content(String),
+    /// Local file errors
+    Io(std::io::Error),
 }
 
 impl AssetError {
@@ -47,10 +57,24 @@ impl AssetError {
             AssetError::Http(e) => err_is_retryable(e),
             AssetError::Jpeg(_) => false,
             AssetError::Content(_) => false,
+            AssetError::Io(_) => false,
         }
     }
 }
 
+impl std::fmt::Display for AssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssetError::Http(e) => write!(f, "HTTP error: {}", e),
+            AssetError::Jpeg(e) => write!(f, "JPEG 2000 decode error: {}", e),
+            AssetError::Content(s) => write!(f, "Content error: {}", s),
+            AssetError::Io(e) => write!(f, "File error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AssetError {}
+
 //
 //  Encapsulate errors from each of the lower level error types
 //
@@ -64,6 +88,11 @@ impl convert::From<jpeg2k::error::Error> for AssetError {
         AssetError::Jpeg(err)
     }
 }
+impl convert::From<std::io::Error> for AssetError {
+    fn from(err: std::io::Error) -> AssetError {
+        AssetError::Io(err)
+    }
+}
 
 /// Data about the image
 #[derive(Debug)]
@@ -72,68 +101,432 @@ pub struct ImageStats {
     bytes_per_pixel: u8,
     /// Original dimensions of image.
     dimensions: (u32, u32),
+    /// Order of packets in the codestream.
+    progression_order: ProgressionOrder,
 }
 
 
+/// Where the bytes of an image come from.
+pub enum ImageSource<'a> {
+    /// Fetch from a server, reading only the bytes needed.
+    Url(&'a ureq::Agent, &'a RetryPolicy, &'a str),
+    /// Fetch from a server through the disk cache. Bytes already in the cache are not fetched again.
+    CachedUrl(&'a ureq::Agent, &'a RetryPolicy, &'a DiskCache, &'a str),
+    /// Already in memory, sent by the requester.
+    Data(&'a [u8]),
+    /// Local file, reading only the bytes needed.
+    File(PathBuf),
+}
+
+impl<'a> ImageSource<'a> {
+    /// Fetch from a server, through the disk cache if there is one.
+    pub fn url(
+        agent: &'a ureq::Agent,
+        retry_policy: &'a RetryPolicy,
+        cache_opt: Option<&'a DiskCache>,
+        url: &'a str,
+    ) -> ImageSource<'a> {
+        match cache_opt {
+            Some(cache) => ImageSource::CachedUrl(agent, retry_policy, cache, url),
+            None => ImageSource::Url(agent, retry_policy, url),
+        }
+    }
+
+    /// Identifies the image, so what is learned from it is counted once.
+    fn image_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self {
+            ImageSource::Url(_, _, url) | ImageSource::CachedUrl(_, _, _, url) => url.hash(&mut hasher),
+            ImageSource::Data(data) => data.hash(&mut hasher),
+            ImageSource::File(path) => path.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    /// Read the bytes in the given range, inclusive, or all of them if no range.
+    /// As with HTTP, a range past the end of the data returns what there is.
+    fn read(&self, byte_range_opt: Option<(u32, u32)>) -> Result<AssetPart, AssetError> {
+        match self {
+            ImageSource::Url(agent, retry_policy, url) => Ok(fetch_asset(agent, retry_policy, url, byte_range_opt)?),
+            ImageSource::CachedUrl(agent, retry_policy, cache, url) => {
+                Ok(fetch_asset_cached(agent, retry_policy, cache, url, byte_range_opt)?)
+            }
+            ImageSource::Data(data) => {
+                let (start, end) = match byte_range_opt {
+                    Some((first, last)) => (first as usize, (last as usize).saturating_add(1)),
+                    None => (0, data.len()),
+                };
+                let end = end.min(data.len());
+                let start = start.min(end);
+                Ok(AssetPart {
+                    data: data[start..end].to_vec(),
+                    start: start as u32,
+                    at_end: end == data.len(),
+                    ..Default::default()
+                })
+            }
+            ImageSource::File(path) => {
+                let mut file = File::open(path)?;
+                let len = file.metadata()?.len();
+                let mut buf = Vec::new();
+                let (first, last) = byte_range_opt.unwrap_or((0, u32::MAX));
+                let first = u64::from(first).min(len);
+                file.seek(SeekFrom::Start(first))?;
+                (&mut file)
+                    .take(u64::from(last).saturating_sub(first) + 1)
+                    .read_to_end(&mut buf)?;
+                Ok(AssetPart {
+                    at_end: first + buf.len() as u64 >= len,
+                    data: buf,
+                    start: first as u32,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
 /// JPEG 2000 image currently being fetched.
 #[derive(Default)]
 pub struct FetchedImage {
     /// First bytes of the input file, if previously fetched.
     beginning_bytes: Vec<u8>,
+    /// True if beginning_bytes is the whole file.
+    at_end: bool,
     /// Image as read, but not exported
     image_opt: Option<jpeg2k::Image>,
+    /// Main header, parsed without the decoder.
+    header_opt: Option<ImageHeader>,
+    /// Discard level of the image as last decoded.
+    discard_level: u32,
+    /// Discard level the image as last decoded has full detail for.
+    /// Higher than discard_level if there were not enough bytes. None if the layout doesn't say.
+    effective_discard_level: Option<u32>,
 }
 
 impl FetchedImage {
-    /// Fetch image from server at indicated size.
-    fn fetch(
+    /// Fetch and decode an image, sized to fit within max_size, or at the requested discard level.
+    /// If neither is specified, the full size image is fetched.
+    ///
+    /// The first fetch gets the header, so the second fetch can read just enough of the file.
+    /// With a disk cache, bytes already in the cache are not fetched again.
+    pub fn fetch_to_size(
         &mut self,
         agent: &ureq::Agent,
+        retry_policy: &RetryPolicy,
+        cache_opt: Option<&DiskCache>,
         url: &str,
         max_size_opt: Option<u32>,
+        discard_opt: Option<u32>,
+    ) -> Result<(), AssetError> {
+        self.load_to_size(&ImageSource::url(agent, retry_policy, cache_opt, url), max_size_opt, discard_opt)
+    }
+
+    /// Decode an image already in memory, sized to fit within max_size, or at the requested discard level.
+    /// If neither is specified, the full size image is decoded.
+    ///
+    /// Only as much of the data as needed for that size is decoded.
+    pub fn decode_to_size(
+        &mut self,
+        data: &[u8],
+        max_size_opt: Option<u32>,
+        discard_opt: Option<u32>,
+    ) -> Result<(), AssetError> {
+        self.load_to_size(&ImageSource::Data(data), max_size_opt, discard_opt)
+    }
+
+    /// Load and decode an image from any source, sized to fit within max_size,
+    /// or at the requested discard level. If neither is specified, the full size image is loaded.
+    pub fn load_to_size(
+        &mut self,
+        source: &ImageSource,
+        max_size_opt: Option<u32>,
+        discard_opt: Option<u32>,
     ) -> Result<(), AssetError> {
-        if self.image_opt.is_none() {
+        /// Enough for the header when we have no better guess.
+        const HEADER_FETCH_SIZE: u32 = 16;
+        self.header_opt = None; // start over
+        self.load(source, Some(max_size_opt.unwrap_or(HEADER_FETCH_SIZE)))?; // first fetch, for header
+        self.upgrade(source, max_size_opt, discard_opt)?; // second fetch, at requested size
+        Ok(())
+    }
+
+    /// Decode an image already loaded at a new size, usually larger.
+    /// Only the bytes not already held are read. The header is not read again.
+    /// Specify max_size or discard level, or neither for full size.
+    ///
+    /// Returns the discard level of the newly decoded image.
+    pub fn upgrade(
+        &mut self,
+        source: &ImageSource,
+        max_size_opt: Option<u32>,
+        discard_opt: Option<u32>,
+    ) -> Result<u32, AssetError> {
+        let stats = self
+            .get_image_stats()
+            .ok_or_else(|| AssetError::Content("Image not fetched".to_string()))?;
+        let max_size_opt = if let Some(discard) = discard_opt {
+            let max_dim = stats.dimensions.0.max(stats.dimensions.1);
+            Some((max_dim >> discard.min(31)).max(1)) // size at that discard level
+        } else {
+            max_size_opt
+        };
+        self.load(source, max_size_opt)?;
+        Ok(self.discard_level)
+    }
+
+    /// True if an image has been loaded, so it can be upgraded.
+    pub fn is_loaded(&self) -> bool {
+        self.header_opt.is_some()
+    }
+
+    /// True if the whole file has been read and decoded at full size,
+    /// so upgrading cannot improve the image.
+    pub fn is_complete(&self) -> bool {
+        self.at_end && self.effective_discard_level == Some(0)
+    }
+
+    /// Discard level of the decoded image. 0 is full size, 1 halves each dimension, etc.
+    pub fn get_discard_level(&self) -> u32 {
+        self.discard_level
+    }
+
+    /// Discard level the decoded image has full detail for. If the file was too short,
+    /// this is higher than the discard level, and the image is blurry.
+    /// None if the codestream layout doesn't say, and only part of the file was read.
+    pub fn get_effective_discard_level(&self) -> Option<u32> {
+        self.effective_discard_level
+    }
+
+    /// The decoded image, converted for the image crate.
+    pub fn get_dynamic_image(&self) -> Result<DynamicImage, AssetError> {
+        if let Some(img) = &self.image_opt {
+            Ok(img.try_into()?)
+        } else {
+            Err(AssetError::Content("Image not fetched".to_string()))
+        }
+    }
+
+    /// Pixels of the decoded image, as raw bytes with no headers.
+    pub fn get_pixels(&self) -> Result<jpeg2k::ImageData, AssetError> {
+        if let Some(img) = &self.image_opt {
+            Ok(img.get_pixels(None)?)
+        } else {
+            Err(AssetError::Content("Image not fetched".to_string()))
+        }
+    }
+
+    /// Drop the decoded image, keeping the file bytes and header, which are all an upgrade needs.
+    /// For images held after their pixels have been copied out.
+    pub fn release_pixels(&mut self) {
+        self.image_opt = None;
+    }
+
+    /// Bytes of the file held.
+    pub fn held_bytes(&self) -> usize {
+        self.beginning_bytes.len()
+    }
+
+    /// Load image from source at indicated size.
+    ///
+    /// The first load reads and parses only the header. After that, loads decode.
+    fn load(&mut self, source: &ImageSource, max_size_opt: Option<u32>) -> Result<(), AssetError> {
+        if self.header_opt.is_none() {
             //  No previous info. Fetch with guess as to size.
             let bounds: Option<(u32, u32)> = if let Some(max_size) = max_size_opt {
                 Some((0, estimate_initial_read_size(max_size))) // first guess
             } else {
                 None
             };
-            ////println!("Bounds: {:?}", bounds); // ***TEMP***
-            let decode_parameters = DecodeParameters::new(); // default decode, best effort
-            self.beginning_bytes = fetch_asset(agent, url, bounds)?; // fetch the asset
-            let decode_result =
-                jpeg2k::Image::from_bytes_with(&self.beginning_bytes, decode_parameters);
-            match decode_result {
-                Ok(v) => self.image_opt = Some(v),
-                Err(e) => return Err(e.into()),
-            };
-            ////self.image_opt = Some(jpeg2k::Image::from_bytes_with(&self.beginning_bytes, decode_parameters).map_err(into)?);
+            self.beginning_bytes.clear();
+            self.at_end = false;
+            self.image_opt = None;
+            self.discard_level = 0;
+            self.effective_discard_level = None;
+            self.read_more(source, bounds)?; // fetch the asset
+            let header = self.read_header(source)?;
+            log::debug!(step = "header", width = header.width, height = header.height, components = header.components.len(),
+                levels = header.resolution_levels, layers = header.layers, bytes = self.beginning_bytes.len();
+                "Header: ({}, {}), {} resolution levels, {:?}", header.width, header.height, header.resolution_levels, header.progression_order);
+            self.header_opt = Some(header);
             self.sanity_check()                     // sanity check before decode
         } else {
             //  We have a previous image and can be more accurate.
-            let stats = self.get_image_stats().unwrap();    // should alwasy get, we just tested for image presence.
-            let (bounds, discard_level) = if let Some(max_size) = max_size_opt {
-                let (max_bytes, discard_level) = estimate_read_size(stats.dimensions, stats.bytes_per_pixel, max_size);
-                (Some((0, max_bytes)), discard_level)  // calc bounds to read
+            let stats = self.get_image_stats().unwrap();    // should alwasy get, we just tested for header presence.
+            //  Can't discard more levels than the image has.
+            let max_discard_level = self.header_opt.as_ref().map_or(0, |h| u32::from(h.resolution_levels).saturating_sub(1));
+            let (bounds, discard_level, exact_opt) = if let Some(max_size) = max_size_opt {
+                let (max_bytes, discard_level) = estimate_read_size(stats.dimensions, stats.bytes_per_pixel, max_size, stats.progression_order, &COMPRESSION_STATS.lock().unwrap());
+                let discard_level = discard_level.min(max_discard_level);
+                //  Use the exact size from the codestream layout if we can get it.
+                let exact_opt = if discard_level > 0 { self.exact_read_size(source, discard_level)? } else { None };
+                let max_bytes = exact_opt.unwrap_or(max_bytes);
+                log::debug!(step = "estimate", max_size = max_size, discard_level = discard_level, max_bytes = max_bytes, exact = exact_opt.is_some();
+                    "Size {} is discard level {}, read through byte {}", max_size, discard_level, max_bytes);
+                (Some((0, max_bytes)), discard_level, exact_opt)  // calc bounds to read
             } else {
-                (None, 0)                                    // caller wants full size
+                (None, 0, None)                                    // caller wants full size
             };
-            //  Now fetch. Currently, from beginning, but we could optimize and reuse the first part.
-            self.beginning_bytes = fetch_asset(agent, url, bounds)?; // fetch the asset
+            //  Now fetch. Only the bytes after the ones we already have.
+            self.read_more(source, bounds)?; // fetch the rest of what we need
+            let effective_discard_level = self.read_rest_of_level(source, discard_level)?;
             let decode_parameters = DecodeParameters::new().reduce(discard_level); // decoded to indicated level
+            let now = std::time::Instant::now();
             let decode_result =
                 jpeg2k::Image::from_bytes_with(&self.beginning_bytes, decode_parameters);
             match decode_result {
                 Ok(v) => self.image_opt = Some(v),
                 Err(e) => return Err(e.into()),
             };
+            //  Learn from the exact size, now that the image has decoded.
+            if let Some(last) = exact_opt {
+                record_compression(source.image_key(), stats.progression_order, discard_level, last as usize + 1, level_pixels(stats.dimensions, discard_level));
+            }
+            self.discard_level = discard_level;
+            self.effective_discard_level = effective_discard_level;
+            log::debug!(step = "decode", discard_level = discard_level, effective_discard_level = effective_discard_level,
+                bytes = self.beginning_bytes.len(), at_end = self.at_end, elapsed_us = now.elapsed().as_micros() as u64;
+                "Decoded {} bytes at discard level {} in {:#?}", self.beginning_bytes.len(), discard_level, now.elapsed());
             self.sanity_check()                     // sanity check before decode
         }
     }
-    
+
+    /// Parse the header, reading more if the header is longer than what we have.
+    fn read_header(&mut self, source: &ImageSource) -> Result<ImageHeader, AssetError> {
+        /// Give up on headers bigger than this. Big ICC profiles can make JP2 headers large.
+        const MAX_HEADER_SIZE: usize = 1024 * 1024;
+        loop {
+            match parse_header(&self.beginning_bytes) {
+                Ok(header) => return Ok(header),
+                Err(HeaderError::Truncated(needed)) if !self.at_end && needed <= MAX_HEADER_SIZE => {
+                    let last = u32::try_from(needed)
+                        .ok()
+                        .and_then(|needed| needed.checked_sub(1))
+                        .ok_or_else(|| AssetError::Content(format!("Bad header length {}", needed)))?;
+                    let have = self.beginning_bytes.len();
+                    self.read_more(source, Some((0, last)))?;
+                    if self.beginning_bytes.len() <= have {
+                        return Err(AssetError::Content(format!("Header needs {} bytes, but got only {}", needed, have)));
+                    }
+                }
+                Err(e) => return Err(AssetError::Content(e.to_string())),
+            }
+        }
+    }
+
+    /// Last byte needed for a discard level, from the codestream layout.
+    /// Tile-part headers are read as needed. Everything before them is needed anyway.
+    /// None if the layout doesn't say, and the size has to be estimated.
+    fn exact_read_size(&mut self, source: &ImageSource, discard_level: u32) -> Result<Option<u32>, AssetError> {
+        /// Read this much past a tile-part header start, so the whole header usually comes in one read.
+        const TILE_PART_HEADER_READ_AHEAD: usize = 256;
+        loop {
+            let header = self.header_opt.as_ref().expect("No header");
+            match bytes_for_discard_level(header, &self.beginning_bytes, discard_level) {
+                Ok(Some(bytes)) => return Ok(u32::try_from(bytes).ok().map(|n| n.saturating_sub(1))),
+                Err(HeaderError::Truncated(needed)) if !self.at_end => {
+                    let have = self.beginning_bytes.len();
+                    let last = needed.saturating_add(TILE_PART_HEADER_READ_AHEAD) - 1;
+                    self.read_more(source, Some((0, u32::try_from(last).unwrap_or(u32::MAX))))?;
+                    if self.beginning_bytes.len() <= have {
+                        return Ok(None); // no progress
+                    }
+                }
+                _ => return Ok(None), // can't tell, estimate
+            }
+        }
+    }
+
+    /// After reading for a discard level, check that we have it all.
+    /// Servers can send less than asked for. Fetch the rest, if the layout says how much that is.
+    /// Returns the effective discard level, if known.
+    fn read_rest_of_level(&mut self, source: &ImageSource, discard_level: u32) -> Result<Option<u32>, AssetError> {
+        let mut effective_discard_level = self.calc_effective_discard_level(discard_level);
+        while effective_discard_level != Some(discard_level) && !self.at_end {
+            let have = self.beginning_bytes.len();
+            //  Finding the size can itself read more, so check again either way.
+            let exact_opt = self.exact_read_size(source, discard_level)?;
+            if let Some(last) = exact_opt {
+                self.read_more(source, Some((0, last)))?;
+            }
+            effective_discard_level = self.calc_effective_discard_level(discard_level);
+            if exact_opt.is_none() || self.beginning_bytes.len() <= have {
+                break; // can't tell how much more, or no progress
+            }
+        }
+        Ok(effective_discard_level)
+    }
+
+    /// Lowest discard level, starting at the one requested, for which we have all the bytes.
+    /// If the layout doesn't say, every level is there if the whole codestream is,
+    /// and otherwise the level is unknown, None.
+    fn calc_effective_discard_level(&self, discard_level: u32) -> Option<u32> {
+        let header = self.header_opt.as_ref()?;
+        let max_discard_level = u32::from(header.resolution_levels).saturating_sub(1);
+        for level in discard_level..=max_discard_level {
+            match bytes_for_discard_level(header, &self.beginning_bytes, level) {
+                Ok(Some(needed)) if needed <= self.beginning_bytes.len() => return Some(level),
+                Ok(Some(_)) | Err(HeaderError::Truncated(_)) => {} // not enough for this level
+                _ => {
+                    //  Can't tell. The whole file, or a read which stopped at the end of codestream marker, has everything.
+                    let complete = self.at_end || self.beginning_bytes.ends_with(&EOC.to_be_bytes());
+                    return complete.then_some(discard_level);
+                }
+            }
+        }
+        Some(max_discard_level) // not even the lowest resolution is complete
+    }
+
+    /// Extend beginning_bytes to cover the range, inclusive, or the whole file if no range.
+    /// Only the bytes not already present are read.
+    fn read_more(&mut self, source: &ImageSource, bounds: Option<(u32, u32)>) -> Result<(), AssetError> {
+        let have = self.beginning_bytes.len() as u32;
+        let last = bounds.map_or(u32::MAX, |(_, last)| last);
+        if self.at_end || (have > 0 && last < have) {
+            return Ok(()); // already have it all
+        }
+        let range = if have == 0 && bounds.is_none() {
+            None // whole file
+        } else {
+            Some((have, last))
+        };
+        let now = std::time::Instant::now();
+        let part = source.read(range)?;
+        log::debug!(step = "read", start = part.start, bytes = part.data.len(), at_end = part.at_end, elapsed_us = now.elapsed().as_micros() as u64;
+            "Read {} bytes at {} in {:#?}", part.data.len(), part.start, now.elapsed());
+        if part.start == 0 {
+            self.beginning_bytes = part.data; // whole file, or server ignored the range
+        } else if part.start == have {
+            self.beginning_bytes.extend_from_slice(&part.data); // just the new part
+        } else {
+            return Err(AssetError::Content(format!(
+                "Requested bytes starting at {}, got bytes starting at {}",
+                have, part.start
+            )));
+        }
+        self.at_end = part.at_end;
+        Ok(())
+    }
+
     /// Image sanity check. Size, precision, etc.
+    /// Checks the header, and the decoded image if any.
     fn sanity_check(&self) -> Result<(), AssetError> {
+        if let Some(header) = &self.header_opt {
+            if header.width > LARGEST_IMAGE_DIMENSION || header.height > LARGEST_IMAGE_DIMENSION {
+                return Err(AssetError::Content(format!("Image dimensions ({},{}) out of range", header.width, header.height)));
+            }
+            if header.components.len() > 4 {
+                return Err(AssetError::Content(format!("Image component count {} of range", header.components.len())));
+            }
+            for component in header.components.iter() {
+                //  Component precision is in bits
+                if component.precision > 16 {
+                    return Err(AssetError::Content(format!("Image component precision {} of range", component.precision)));
+                }
+            }
+        } else {
+            return Err(AssetError::Content("Image not fetched".to_string()));
+        }
         if let Some(img) = &self.image_opt {
             if img.orig_width() < 1 || img.orig_width() > LARGEST_IMAGE_DIMENSION
             || img.orig_height() < 1 || img.orig_height() > LARGEST_IMAGE_DIMENSION {
@@ -147,27 +540,18 @@ impl FetchedImage {
                 if component.precision() < 1 || component.precision() > 16 {
                     return Err(AssetError::Content(format!("Image component precision {} of range", component.precision())));
                 }
-            }                
-            Ok(())
-        } else {
-            Err(AssetError::Content(format!("Image not fetched")))
+            }
         }
+        Ok(())
     }
-    
-    /// Statistics about the image
+
+    /// Statistics about the image, from the header.
     fn get_image_stats(&self) -> Option<ImageStats> {
-        if let Some(img) = &self.image_opt {
-            let mut bits_per_pixel = 0;
-            for component in img.components().iter() {
-                bits_per_pixel += component.precision()
-            }
-            Some(ImageStats {
-                dimensions: (img.orig_width(), img.orig_height()),
-                bytes_per_pixel: ((bits_per_pixel + 7) / 8) as u8,
-            })
-        } else {
-            None
-        }
+        self.header_opt.as_ref().map(|header| ImageStats {
+            dimensions: (header.width, header.height),
+            bytes_per_pixel: header.bytes_per_pixel(),
+            progression_order: header.progression_order,
+        })
     }
 }
 
@@ -181,6 +565,7 @@ const LARGEST_IMAGE_DIMENSION: u32 = 8192;
 
 /// Estimate amount of data to read for a desired resolution.
 /// This should overestimate, so we read enough.
+/// What has been learned about files of the same progression order is used, if enough.
 ///
 /// Returns (max bytes, discard level).
 /// Discard level 0 is full size, 1 is 1/4 size, etc.
@@ -188,6 +573,8 @@ pub fn estimate_read_size(
     image_size: (u32, u32),
     bytes_per_pixel: u8,
     max_dim: u32,
+    order: ProgressionOrder,
+    stats: &CompressionStats,
 ) -> (u32, u32) {
     assert!(max_dim > 0); // would cause divide by zero
     let reduction_ratio = (image_size.0.max(image_size.1)) as u32 / (max_dim as u32);
@@ -198,18 +585,28 @@ pub fn estimate_read_size(
     let in_pixels = image_size.0 * image_size.1;
     let out_pixels = in_pixels / (reduction_ratio * reduction_ratio); // number of pixels desired in output
     
-       //  Read this many bytes and decode.
-    let max_bytes = (((out_pixels as f32) * (bytes_per_pixel as f32)) * JPEG_2000_COMPRESSION_FACTOR) as u32;
-    let max_bytes = max_bytes.max(MINIMUM_SIZE_TO_READ);
     //  Reduction ratio 1 -> discard level 0, 4->1, 16->2, etc. Round down.
     let discard_level = calc_discard_level(reduction_ratio); // ***SCALE***
-    println!(
-        "Reduction ratio: {}, discard level {}, bytes to read = {}",
-        reduction_ratio, discard_level, max_bytes
-    ); // ***TEMP***
+       //  Read this many bytes and decode. Use what has been learned about this discard level, if enough.
+    let max_bytes = match stats.bytes_per_pixel(order, discard_level) {
+        Some(bytes_per_out_pixel) => (level_pixels(image_size, discard_level) as f64 * bytes_per_out_pixel) as u32,
+        None => (((out_pixels as f32) * (bytes_per_pixel as f32)) * JPEG_2000_COMPRESSION_FACTOR) as u32,
+    };
+    let max_bytes = max_bytes.max(MINIMUM_SIZE_TO_READ);
     (max_bytes, discard_level)
 }
 
+/// An effective discard level, for messages. "unknown" if None.
+pub fn level_text(level: Option<u32>) -> String {
+    level.map_or("unknown".to_string(), |level| level.to_string())
+}
+
+/// Number of pixels in an image of this size at a discard level.
+fn level_pixels(image_size: (u32, u32), discard_level: u32) -> u64 {
+    let scale = 1_u32 << discard_level.min(31);
+    u64::from(image_size.0.div_ceil(scale)) * u64::from(image_size.1.div_ceil(scale))
+}
+
 ///  Reduction ratio 1 -> discard level 0, 2->1, 3->2, etc. Round up. Just log2.
 //  Yes, there is a cleverer way to do this by shifting and masking.
 fn calc_discard_level(reduction_ratio: u32) -> u32 {
@@ -256,37 +653,130 @@ fn test_calc_discard_level() {
 fn test_estimate_read_size() {
     /// Assume RGBA, 8 bits   
     const BYTES_PER_PIXEL: u8 = 4;
+    let mut stats = CompressionStats::new(); // nothing learned yet
     //  Don't know size of JPEG 2000 image.
     assert_eq!(estimate_initial_read_size(1), MINIMUM_SIZE_TO_READ);
     assert_eq!(estimate_initial_read_size(64), 14745); // given constant values above, 90% of output image area.
     assert_eq!(estimate_initial_read_size(32), MINIMUM_SIZE_TO_READ.max(3686)); // given constant values above, 90% of output image area.
                                                       //  Know size of JPEG 2000 image.
     assert_eq!(
-        estimate_read_size((64, 64), BYTES_PER_PIXEL, 64),
+        estimate_read_size((64, 64), BYTES_PER_PIXEL, 64, ProgressionOrder::Rpcl, &stats),
         (u32::MAX, 0)
     );
-    assert_eq!(estimate_read_size((64, 64), BYTES_PER_PIXEL, 32), (MINIMUM_SIZE_TO_READ.max(3686), 1)); // 2:1 reduction
+    assert_eq!(estimate_read_size((64, 64), BYTES_PER_PIXEL, 32, ProgressionOrder::Rpcl, &stats), (MINIMUM_SIZE_TO_READ.max(3686), 1)); // 2:1 reduction
     assert_eq!(
-        estimate_read_size((512, 512), BYTES_PER_PIXEL, 32),
+        estimate_read_size((512, 512), BYTES_PER_PIXEL, 32, ProgressionOrder::Rpcl, &stats),
         (MINIMUM_SIZE_TO_READ.max(3686), 4)
     ); // 16:1 reduction, discard level 4
     assert_eq!(
-        estimate_read_size((512, 512), BYTES_PER_PIXEL, 64),
+        estimate_read_size((512, 512), BYTES_PER_PIXEL, 64, ProgressionOrder::Rpcl, &stats),
         (14745, 3)
     ); // 8:1 reduction, discard level 3
     assert_eq!(
-        estimate_read_size((512, 256), BYTES_PER_PIXEL, 64),
+        estimate_read_size((512, 256), BYTES_PER_PIXEL, 64, ProgressionOrder::Rpcl, &stats),
         (7372, 3)
     ); // 8:1 reduction, discard level 3
     assert_eq!(
-        estimate_read_size((512, 256), BYTES_PER_PIXEL, 512),
+        estimate_read_size((512, 256), BYTES_PER_PIXEL, 512, ProgressionOrder::Rpcl, &stats),
         (u32::MAX, 0)
     ); // no reduction, full size.
+    //  Learned ratio, 0.5 bytes per output pixel at discard level 3, replaces the guess.
+    for _ in 0..8 {
+        stats.record(ProgressionOrder::Rpcl, 3, 2048, 64 * 64);
+    }
+    assert_eq!(
+        estimate_read_size((512, 512), BYTES_PER_PIXEL, 64, ProgressionOrder::Rpcl, &stats),
+        (2048, 3)
+    );
+    assert_eq!(
+        estimate_read_size((512, 512), BYTES_PER_PIXEL, 64, ProgressionOrder::Lrcp, &stats),
+        (14745, 3)
+    ); // other orders unchanged
+    assert_eq!(estimate_read_size((512, 512), BYTES_PER_PIXEL, 32, ProgressionOrder::Rpcl, &stats), (MINIMUM_SIZE_TO_READ.max(3686), 4)); // other levels unchanged
+}
+
+#[test]
+/// Memory and file sources read ranges the same way as HTTP.
+fn test_image_source_read() {
+    let data: Vec<u8> = (0..100).collect();
+    let path = std::env::temp_dir().join(format!("jpeg2000-decoder-source-{}.j2k", std::process::id()));
+    std::fs::write(&path, &data).expect("Unable to write test file");
+    for source in [ImageSource::Data(&data), ImageSource::File(path.clone())] {
+        let read = |range| {
+            let part = source.read(range).unwrap();
+            (part.data, part.start, part.at_end)
+        };
+        assert_eq!(read(None), (data.clone(), 0, true));
+        assert_eq!(read(Some((0, 9))), (data[0..10].to_vec(), 0, false)); // range is inclusive
+        assert_eq!(read(Some((90, 1000))), (data[90..].to_vec(), 90, true)); // past end
+        assert_eq!(read(Some((200, 300))), (Vec::new(), 100, true));
+    }
+    std::fs::remove_file(&path).unwrap();
+    assert!(ImageSource::File(path).read(None).is_err()); // no file
+}
+
+#[test]
+/// Reading more of a file appends only the new bytes.
+fn test_read_more() {
+    let data: Vec<u8> = (0..100).collect();
+    let source = ImageSource::Data(&data);
+    let mut image = FetchedImage::default();
+    image.read_more(&source, Some((0, 9))).unwrap();
+    assert_eq!(image.beginning_bytes, data[0..10]);
+    image.read_more(&source, Some((0, 5))).unwrap(); // already have it
+    assert_eq!(image.beginning_bytes, data[0..10]);
+    image.read_more(&source, Some((0, 49))).unwrap();
+    assert_eq!(image.beginning_bytes, data[0..50]);
+    assert!(!image.at_end);
+    image.read_more(&source, None).unwrap(); // rest of file
+    assert_eq!(image.beginning_bytes, data);
+    assert!(image.at_end);
+}
+
+/// Effective discard level of a partly read file, and fetching the rest of a level.
+#[test]
+fn test_effective_discard_level() {
+    use jpeg2000_decoder::testdata::codestream;
+    let (file, ends) = codestream(1); // RLCP, so the layout is known
+    //  Just the main header, as if that was all the server sent.
+    let with_bytes = |n: usize| {
+        let header = parse_header(&file).unwrap();
+        let mut image = FetchedImage::default();
+        image.beginning_bytes = file[..n.max(header.main_header_end)].to_vec();
+        image.header_opt = Some(header);
+        image
+    };
+    assert_eq!(with_bytes(ends[1]).calc_effective_discard_level(2), Some(2));
+    assert_eq!(with_bytes(ends[1] - 1).calc_effective_discard_level(2), Some(3)); // lowest resolution only
+    assert_eq!(with_bytes(ends[3]).calc_effective_discard_level(0), Some(0));
+    assert_eq!(with_bytes(ends[0] - 1).calc_effective_discard_level(1), Some(3)); // nothing complete
+    //  The rest of the level is fetched.
+    let mut image = with_bytes(0);
+    assert_eq!(image.read_rest_of_level(&ImageSource::Data(&file), 1).unwrap(), Some(1));
+    assert!(image.beginning_bytes.len() >= ends[2] && !image.at_end);
+    //  A file which is too short gives a blurry image.
+    let short = &file[..ends[2] - 10];
+    let mut image = with_bytes(0);
+    assert_eq!(image.read_rest_of_level(&ImageSource::Data(short), 0).unwrap(), Some(2));
+    assert!(image.at_end);
+    //  Layer-major order, so the layout doesn't say. Only the whole codestream is known to be complete.
+    let (file, ends) = codestream(0);
+    let header = parse_header(&file).unwrap();
+    let mut image = FetchedImage::default();
+    image.header_opt = Some(header);
+    image.beginning_bytes = file[..ends[2]].to_vec();
+    assert_eq!(image.calc_effective_discard_level(1), None); // unknown
+    assert_eq!(image.read_rest_of_level(&ImageSource::Data(&file), 1).unwrap(), None); // can't tell how much more
+    image.beginning_bytes = file.clone(); // ends with EOC
+    assert_eq!(image.calc_effective_discard_level(1), Some(1));
+    image.beginning_bytes = file[..ends[2]].to_vec();
+    image.at_end = true;
+    assert_eq!(image.calc_effective_discard_level(1), Some(1));
 }
 
 #[test]
 fn fetch_test_texture() {
-    use crate::DynamicImage;
+    use crate::fetch::{build_agent, DEFAULT_NETWORK_TIMEOUT};
     use image::GenericImageView;
     const TEXTURE_DEFAULT: &str = "89556747-24cb-43ed-920b-47caed15465f"; // plywood in both Second Life and Open Simulator
     const TEXTURE_CAP: &str = "http://asset-cdn.glb.agni.lindenlab.com";
@@ -294,9 +784,9 @@ fn fetch_test_texture() {
     const TEXTURE_OUT_SIZE: Option<u32> = Some(16);
     let url = format!("{}/?texture_id={}", TEXTURE_CAP, TEXTURE_DEFAULT);
     println!("Asset url: {}", url);
-    let agent = build_agent(USER_AGENT, 1);
+    let agent = build_agent(USER_AGENT, 1, DEFAULT_NETWORK_TIMEOUT);
     let mut image = FetchedImage::default();
-    image.fetch(&agent, &url, TEXTURE_OUT_SIZE).expect("Fetch failed");
+    image.load_to_size(&ImageSource::Url(&agent, &RetryPolicy::default(), &url), TEXTURE_OUT_SIZE, None).expect("Fetch failed");
     assert!(image.image_opt.is_some()); // got image
     println!("Image stats: {:?}", image.get_image_stats());
     let img: DynamicImage = (&image.image_opt.unwrap())
@@ -315,7 +805,7 @@ fn fetch_test_texture() {
 
 #[test]
 fn fetch_multiple_textures_serial() {
-    use crate::DynamicImage;
+    use crate::fetch::{build_agent, DEFAULT_NETWORK_TIMEOUT};
     use image::GenericImageView;
     use std::io::BufRead;
     ////const TEST_UUIDS: &str = "samples/smalluuidlist.txt"; // test of UUIDs, relative to manifest dir
@@ -329,13 +819,13 @@ fn fetch_multiple_textures_serial() {
         let now = std::time::Instant::now();
         let mut image = FetchedImage::default();
         // First fetch
-        image.fetch(&agent, &url, Some(16)).expect("Fetch failed");
+        image.load(&ImageSource::Url(agent, &RetryPolicy::default(), &url), Some(16)).expect("Fetch failed");
         let fetch_time = now.elapsed();
         let now = std::time::Instant::now();
-        assert!(image.image_opt.is_some()); // got image
+        assert!(image.header_opt.is_some()); // got header
         println!("Image stats: {:?}", image.get_image_stats());
         //  Second fetch, now that we have header info
-        image.fetch(&agent, &url, Some(max_size)).expect("Fetch failed");
+        image.load(&ImageSource::Url(agent, &RetryPolicy::default(), &url), Some(max_size)).expect("Fetch failed");
         let img: DynamicImage = (&image.image_opt.unwrap())
             .try_into()
             .expect("Conversion failed"); // convert
@@ -359,7 +849,7 @@ fn fetch_multiple_textures_serial() {
     let file = std::fs::File::open(format!("{}/{}", basedir, TEST_UUIDS)).expect("Unable to open file of test UUIDs");
     let reader = std::io::BufReader::new(file);
     const TEXTURE_OUT_SIZE: u32 = 128;
-    let agent = build_agent(USER_AGENT, 1);
+    let agent = build_agent(USER_AGENT, 1, DEFAULT_NETWORK_TIMEOUT);
     for line in reader.lines() { 
         let line = line.expect("Error reading UUID file");
         let line = line.trim();
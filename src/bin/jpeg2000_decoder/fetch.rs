@@ -6,28 +6,277 @@
 //  Loader for mesh and sculpt assets.
 //  Called from threads in the asset load thread pool.
 //
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use ureq::{Agent, AgentBuilder};
 
+/// User agent for HTTP requests, if none is specified.
+pub const DEFAULT_USER_AGENT: &str = concat!("jpeg2000-decoder/", env!("CARGO_PKG_VERSION"));
+
 /// Something has gone wrong if idle for this long.
-const NETWORK_TIMEOUT: Duration = Duration::from_secs(15);
+pub const DEFAULT_NETWORK_TIMEOUT: Duration = Duration::from_secs(15);
 
 /// Is this HTTP fetch error retryable?
+///
+/// Retryable errors are ones where the same request might work later: network failures,
+/// timeouts, throttling, and server trouble. Errors in the request itself, or which say
+/// the asset is not there, are not retried.
+/// A 416 reply, range not satisfiable, is handled separately, by asking for the whole asset.
 pub fn err_is_retryable(e: &ureq::Error) -> bool {
+    use ureq::ErrorKind;
     match e {
-        ureq::Error::Transport(_) => true, // always retry network errors
-        ureq::Error::Status(status_code, _) => {
-            match status_code {
-                400 => false, // bad request
-                401 => false, // forbidden
-                402 => false, // payment required
-                403 => false, // forbidden
-                404 => false, // File not found, do not retry.
-                405 => false, // method not allowed
-                _ => true,    // retry everything else
+        ureq::Error::Transport(t) => match t.kind() {
+            ErrorKind::ConnectionFailed => true, // refused, reset, or timed out
+            ErrorKind::Io => true,               // failed partway through
+            ErrorKind::BadStatus => true,        // garbled reply, maybe a proxy or a dropped connection
+            ErrorKind::ProxyConnect => true,     // proxy trouble
+            //  A temporary DNS failure is worth retrying. "No such host" is not.
+            ErrorKind::Dns => {
+                let source = std::error::Error::source(e).map(|s| s.to_string()).unwrap_or_default();
+                dns_failure_is_temporary(&format!("{} {}", t.message().unwrap_or_default(), source)) // not the URL
             }
+            ErrorKind::InvalidUrl => false,
+            ErrorKind::UnknownScheme => false,
+            ErrorKind::InsecureRequestHttpsOnly => false,
+            ErrorKind::TooManyRedirects => false, // redirect loop
+            ErrorKind::BadHeader => false,
+            ErrorKind::InvalidProxyUrl => false,
+            ErrorKind::ProxyUnauthorized => false,
+            ErrorKind::HTTP => false,
+        },
+        ureq::Error::Status(status_code, _) => match status_code {
+            408 => true, // request timeout
+            421 => true, // misdirected request, may work on a new connection
+            425 => true, // too early
+            429 => true, // too many requests, throttled
+            400..=499 => false, // bad request, forbidden, not found, gone, URI too long, etc.
+            501 => false, // not implemented
+            505 => false, // HTTP version not supported
+            511 => false, // network authentication required
+            _ => true,    // other server errors, such as 500, 502, 503, 504
+        },
+    }
+}
+
+/// Does a DNS error message say the failure is temporary?
+/// The resolver's message is all there is to go on. Wording varies by platform.
+fn dns_failure_is_temporary(msg: &str) -> bool {
+    let msg = msg.to_ascii_lowercase();
+    msg.contains("temporary") || msg.contains("try again")
+}
+
+/// What the server said identifies this version of an asset.
+/// Sent back to the server to ask if a cached copy is still current.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Validators {
+    /// ETag header
+    pub etag: Option<String>,
+    /// Last-Modified header
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// True if there is nothing to validate with.
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Fill in anything missing from an older set. A 304 reply need not repeat every validator.
+    pub fn or(self, older: &Validators) -> Validators {
+        Validators {
+            etag: self.etag.or_else(|| older.etag.clone()),
+            last_modified: self.last_modified.or_else(|| older.last_modified.clone()),
+        }
+    }
+}
+
+/// Condition on a fetch, for a copy of the asset we already have.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Condition<'a> {
+    /// Unconditional
+    #[default]
+    None,
+    /// Reply 304, with no bytes, if our copy is current.
+    /// Uses If-None-Match and If-Modified-Since.
+    IfModified(&'a Validators),
+    /// Send the range only if our copy is current, otherwise the whole asset.
+    /// Uses If-Range.
+    IfUnchanged(&'a Validators),
+}
+
+/// One retry, as reported to the retry hook.
+#[derive(Debug)]
+pub struct RetryEvent<'a> {
+    /// What was being fetched
+    pub url: &'a str,
+    /// Which retry this is. 1 is the first.
+    pub retry: u32,
+    /// Wait before the retry.
+    pub delay: Duration,
+    /// Why the last try failed.
+    pub error: &'a ureq::Error,
+}
+
+/// Called before each retry. Can capture state, and is shared by clones of the policy.
+pub type RetryHook = Arc<dyn Fn(&RetryEvent) + Send + Sync>;
+
+/// How failed fetches are retried.
+///
+/// Waits double after each failure, starting at base_delay, up to max_delay.
+/// Each wait is shortened by a random part, up to the jitter fraction, so that
+/// clients throttled at the same moment do not all come back at the same moment.
+/// A Retry-After on a 429 or 503 reply is the shortest wait. If it is longer than
+/// max_delay, there is no retry.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Tries in all, including the first. 1 means no retries.
+    pub max_attempts: u32,
+    /// Wait before the first retry.
+    pub base_delay: Duration,
+    /// Longest wait before a retry.
+    pub max_delay: Duration,
+    /// Random part of each wait, as a fraction, 0.0 to 1.0.
+    pub jitter: f64,
+    /// Called before each retry. Retries are also logged.
+    pub on_retry: Option<RetryHook>,
+}
+
+impl std::fmt::Debug for RetryPolicy {
+    /// The retry hook is shown only as present or not.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("base_delay", &self.base_delay)
+            .field("max_delay", &self.max_delay)
+            .field("jitter", &self.jitter)
+            .field("on_retry", &self.on_retry.is_some())
+            .finish()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            on_retry: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before a retry, or None if there should be no retry. Retry 1 is the first.
+    /// Random is from 0.0 to 1.0.
+    fn delay(&self, retry: u32, retry_after: Option<Duration>, random: f64) -> Option<Duration> {
+        if retry >= self.max_attempts {
+            return None;
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_delay);
+        let backoff = backoff.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random.clamp(0.0, 1.0));
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None, // server wants us to wait longer than we will
+            Some(retry_after) => Some(backoff.max(retry_after)),
+            None => Some(backoff),
         }
-    }        
+    }
+}
+
+/// Random number from 0.0 to 1.0, for jitter. Xorshift, seeded from the clock.
+/// Not for anything where predictability matters.
+fn random_fraction() -> f64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    let step = |mut x: u64| {
+        if x == 0 {
+            x = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |t| t.as_nanos() as u64) | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        x
+    };
+    let x = match STATE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x))) {
+        Ok(prev) => step(prev),
+        Err(prev) => prev, // cannot happen; the update always succeeds
+    };
+    (x >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+/// How long a server says to wait, from a Retry-After header on a 429 or 503 reply.
+fn retry_after(e: &ureq::Error) -> Option<Duration> {
+    match e {
+        ureq::Error::Status(429 | 503, response) => parse_retry_after(response.header("Retry-After")?, SystemTime::now()),
+        _ => None,
+    }
+}
+
+/// Parse a Retry-After header. Either seconds, or an HTTP date, "Sun, 06 Nov 1994 08:49:37 GMT".
+fn parse_retry_after(s: &str, now: SystemTime) -> Option<Duration> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    //  HTTP date. Only the standard form, which is all servers are supposed to send.
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    if fields.len() != 6 || fields[5] != "GMT" {
+        return None;
+    }
+    let day: u64 = fields[1].parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == fields[2])? as u64 + 1;
+    let year: u64 = fields[3].parse().ok()?;
+    let time: Vec<u64> = fields[4].split(':').map(|f| f.parse().ok()).collect::<Option<_>>()?;
+    if time.len() != 3
+        || !(1..=31).contains(&day)
+        || !(1970..=9999).contains(&year)
+        || time[0] > 23
+        || time[1] > 60
+        || time[2] > 60
+    {
+        return None;
+    }
+    //  Days since 1970-01-01, from the civil calendar. Howard Hinnant's algorithm.
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y / 400;
+    let yoe = y % 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = (era * 146097 + doe).checked_sub(719468)?;
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(time[0] * 3600 + time[1] * 60 + time[2])?;
+    let when = UNIX_EPOCH.checked_add(Duration::from_secs(secs))?;
+    Some(when.duration_since(now).unwrap_or(Duration::ZERO)) // a time already past means now
+}
+
+/// Part of an asset, as fetched.
+#[derive(Debug, Default)]
+pub struct AssetPart {
+    /// The bytes
+    pub data: Vec<u8>,
+    /// Offset of the first byte in the asset.
+    /// Servers may ignore a byte range and send the whole asset, starting at 0.
+    pub start: u32,
+    /// True if this part runs to the end of the asset.
+    pub at_end: bool,
+    /// Server replied 304 to a conditional fetch. Our copy is current, and there are no bytes.
+    pub not_modified: bool,
+    /// Identifies this version of the asset, if the server says.
+    pub validators: Validators,
+}
+
+/// Parse a Content-Range header, "bytes first-last/total".
+/// Returns (first, last, total). Total is None if the server reports "*".
+fn parse_content_range(s: &str) -> Option<(u32, u32, Option<u32>)> {
+    let (range, total) = s.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let total = if total == "*" { None } else { Some(total.parse().ok()?) };
+    Some((first.parse().ok()?, last.parse().ok()?, total))
 }
 
 /// Fetch asset from asset server.
@@ -36,7 +285,8 @@ fn fetch_asset_once(
     agent: &Agent,
     url: &str,
     byte_range_opt: Option<(u32, u32)>,
-) -> Result<Vec<u8>, ureq::Error> {
+    condition: Condition,
+) -> Result<AssetPart, ureq::Error> {
     //  Build query, which may have a byte range specified.
     let query = if let Some(byte_range) = byte_range_opt {
         agent.get(&url).set(
@@ -46,47 +296,137 @@ fn fetch_asset_once(
     } else {
         agent.get(&url)
     };
+    //  Ask about our copy, if we have one.
+    let query = match condition {
+        Condition::None => query,
+        Condition::IfModified(validators) => {
+            let query = match &validators.etag {
+                Some(etag) => query.set("If-None-Match", etag),
+                None => query,
+            };
+            match &validators.last_modified {
+                Some(last_modified) => query.set("If-Modified-Since", last_modified),
+                None => query,
+            }
+        }
+        //  If-Range takes one validator. The ETag is the stronger one.
+        Condition::IfUnchanged(validators) => match (&validators.etag, &validators.last_modified) {
+            (Some(etag), _) if byte_range_opt.is_some() => query.set("If-Range", etag),
+            (None, Some(last_modified)) if byte_range_opt.is_some() => query.set("If-Range", last_modified),
+            _ => query,
+        },
+    };
     //  HTTP/HTTPS read.
     let resp = query.call()?;
+    let validators = Validators {
+        etag: resp.header("ETag").map(str::to_string),
+        last_modified: resp.header("Last-Modified").map(str::to_string),
+    };
+    if resp.status() == 304 {
+        log::debug!(step = "http", url = url, status = 304; "HTTP 304, not modified, from {}", url);
+        return Ok(AssetPart {
+            not_modified: true,
+            validators,
+            ..Default::default()
+        });
+    }
+    //  206 means we got the range requested. Anything else is the whole asset.
+    let content_range = if resp.status() == 206 {
+        resp.header("Content-Range").and_then(parse_content_range)
+    } else {
+        None
+    };
+    let partial = resp.status() == 206;
+    log::debug!(step = "http", url = url, status = resp.status(), partial = partial; "HTTP {} from {}", resp.status(), url);
     let mut buffer = Vec::new();
     resp.into_reader().read_to_end(&mut buffer)?;
-    Ok(buffer)
+    let part = match (partial, content_range, byte_range_opt) {
+        (true, Some((first, last, total)), _) => AssetPart {
+            data: buffer,
+            start: first,
+            at_end: total.is_some_and(|total| last.saturating_add(1) >= total),
+            not_modified: false,
+            validators,
+        },
+        //  Partial, but no usable Content-Range. Assume the range we asked for.
+        (true, None, Some((first, last))) => AssetPart {
+            at_end: (buffer.len() as u64) < u64::from(last) - u64::from(first) + 1,
+            data: buffer,
+            start: first,
+            not_modified: false,
+            validators,
+        },
+        _ => AssetPart {
+            data: buffer,
+            start: 0,
+            at_end: true,
+            not_modified: false,
+            validators,
+        },
+    };
+    Ok(part)
 }
 
 /// Fetch asset from asset server, with retries
 /// Returns ureq::Error, so we can distinguish retryable errors.
-//  This should log retries, but we currently have no way to report them.
 pub fn fetch_asset(
     agent: &Agent,
+    retry_policy: &RetryPolicy,
     url: &str,
     byte_range_opt: Option<(u32, u32)>,
-) -> Result<Vec<u8>, ureq::Error> {
-    const FETCH_RETRIES: usize = 3; // try this many times
-    const FETCH_RETRY_WAIT: std::time::Duration = std::time::Duration::from_secs(2);    // wait between tries
-    let mut retries = FETCH_RETRIES;
+) -> Result<AssetPart, ureq::Error> {
+    fetch_asset_conditional(agent, retry_policy, url, byte_range_opt, Condition::None)
+}
+
+/// Fetch asset from asset server, with retries, on a condition about a copy we already have.
+/// Returns ureq::Error, so we can distinguish retryable errors.
+pub fn fetch_asset_conditional(
+    agent: &Agent,
+    retry_policy: &RetryPolicy,
+    url: &str,
+    byte_range_opt: Option<(u32, u32)>,
+    condition: Condition,
+) -> Result<AssetPart, ureq::Error> {
+    let mut byte_range_opt = byte_range_opt;
+    let mut retry = 0;
     loop {              // until success, or fail
-        match fetch_asset_once(agent, url, byte_range_opt) {
+        match fetch_asset_once(agent, url, byte_range_opt, condition) {
             Ok(v) => return Ok(v),
+            //  Range not satisfiable. The asset is shorter than where the range starts. Ask for all of it.
+            Err(ureq::Error::Status(416, _)) if byte_range_opt.is_some() => {
+                log::debug!(step = "http", url = url, status = 416; "Range not satisfiable for {}, fetching whole asset", url);
+                byte_range_opt = None;
+            }
             Err(e) => {
-                if err_is_retryable(&e) && retries > 0 {
-                    std::thread::sleep(FETCH_RETRY_WAIT);   // wait before retry
-                    retries -= 1;
+                retry += 1;
+                let delay_opt = if err_is_retryable(&e) {
+                    retry_policy.delay(retry, retry_after(&e), random_fraction())
                 } else {
+                    None
+                };
+                let Some(delay) = delay_opt else {
                     return Err(e);     // not retryable or out of retries, fails
+                };
+                log::warn!(step = "retry", url = url, retry = retry, delay_ms = delay.as_millis() as u64;
+                    "Retry {} of {} in {:#?}: {}", retry, url, delay, e);
+                if let Some(on_retry) = &retry_policy.on_retry {
+                    on_retry(&RetryEvent { url, retry, delay, error: &e });
                 }
+                std::thread::sleep(delay);   // wait before retry
             }
         }
     }
 }
 
 /// Build user agent for queries.
-pub fn build_agent(user_agent: &str, max_connections: usize) -> Agent {
+/// The timeout applies to connecting, and to each read and write.
+pub fn build_agent(user_agent: &str, max_connections: usize, timeout: Duration) -> Agent {
     AgentBuilder::new()
         .user_agent(user_agent)
         .max_idle_connections_per_host(max_connections) // we mostly hit the same host, so we want more idle connections available
-        .timeout_connect(NETWORK_TIMEOUT)
-        .timeout_read(NETWORK_TIMEOUT)
-        .timeout_write(NETWORK_TIMEOUT)
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .timeout_write(timeout)
         .build()
 }
 
@@ -95,12 +435,189 @@ fn test_fetch_asset() {
     const USER_AGENT: &str = "Test asset fetcher. Contact info@animats.com if problems.";
     const MAX_CONNECTIONS: usize = 1; // don't overdo
     const URL1: &str = "http://www.example.com"; // something to read
-    let agent = build_agent(USER_AGENT, MAX_CONNECTIONS);
-    let result = fetch_asset(&agent, URL1, Some((0, 200))); // first 200 bytes only
+    let agent = build_agent(USER_AGENT, MAX_CONNECTIONS, DEFAULT_NETWORK_TIMEOUT);
+    let result = fetch_asset(&agent, &RetryPolicy::default(), URL1, Some((0, 200))); // first 200 bytes only
     match result {
-        Ok(text) => {
-            println!("Fetched {:?}", text);
+        Ok(part) => {
+            println!("Fetched {:?}", part.data);
         }
         Err(e) => panic!("Error: {:?}", e),
     }
 }
+
+#[test]
+fn test_parse_content_range() {
+    assert_eq!(parse_content_range("bytes 0-199/1000"), Some((0, 199, Some(1000))));
+    assert_eq!(parse_content_range("bytes 200-999/*"), Some((200, 999, None)));
+    assert_eq!(parse_content_range("bytes */1000"), None); // unsatisfied range
+    assert_eq!(parse_content_range("items 0-1/2"), None);
+}
+
+/// A local HTTP server for tests. Answers each connection with the next canned response,
+/// then returns the requests it got.
+#[cfg(test)]
+pub fn serve_responses(responses: Vec<String>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    use std::io::{BufRead, BufReader, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/image.j2k", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            while reader.read_line(&mut request).unwrap() > 2 {} // to the blank line
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            requests.push(request);
+        }
+        requests
+    });
+    (url, handle)
+}
+
+#[test]
+fn test_retry_policy() {
+    let policy = RetryPolicy {
+        max_attempts: 5,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(1000),
+        jitter: 0.5,
+        on_retry: None,
+    };
+    assert_eq!(policy.delay(1, None, 0.0), Some(Duration::from_millis(100)));
+    assert_eq!(policy.delay(3, None, 0.0), Some(Duration::from_millis(400)));
+    assert_eq!(policy.delay(4, None, 0.0), Some(Duration::from_millis(800)));
+    assert_eq!(policy.delay(4, None, 1.0), Some(Duration::from_millis(400))); // jitter
+    assert_eq!(policy.delay(5, None, 0.0), None); // out of tries
+    assert_eq!(policy.delay(2, Some(Duration::from_millis(900)), 0.0), Some(Duration::from_millis(900))); // Retry-After
+    assert_eq!(policy.delay(2, Some(Duration::from_secs(60)), 0.0), None); // too long to wait
+    for _ in 0..100 {
+        assert!((0.0..=1.0).contains(&random_fraction()));
+    }
+    //  Retry-After
+    let now = UNIX_EPOCH + Duration::from_secs(784111777); // Sun, 06 Nov 1994 08:49:37 GMT
+    assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+    assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:51:37 GMT", now), Some(Duration::from_secs(120)));
+    assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now), Some(Duration::ZERO)); // past
+    assert_eq!(parse_retry_after("soon", now), None);
+    assert_eq!(parse_retry_after("Sun, 06 Nov 400000000000 08:49:37 GMT", now), None); // year out of range
+    assert_eq!(parse_retry_after("Sun, 06 Nov 1994 24:49:37 GMT", now), None); // bad hour
+    assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:99999999999999:37 GMT", now), None); // huge time
+    assert_eq!(parse_retry_after("Fri, 31 Dec 9999 23:59:60 GMT", now).map(|d| d > Duration::ZERO), Some(true));
+}
+
+#[test]
+fn test_fetch_retries() {
+    use std::sync::atomic::AtomicU32;
+    let retries = Arc::new(AtomicU32::new(0));
+    let counter = Arc::clone(&retries);
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(10),
+        max_delay: Duration::from_secs(2),
+        jitter: 0.0,
+        on_retry: Some(Arc::new(move |event: &RetryEvent| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            assert_eq!(event.delay, if event.retry == 1 { Duration::from_secs(1) } else { Duration::from_millis(20) });
+        })),
+    };
+    let (url, server) = serve_responses(vec![
+        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello".to_string(),
+    ]);
+    let agent = build_agent(DEFAULT_USER_AGENT, 1, DEFAULT_NETWORK_TIMEOUT);
+    let part = fetch_asset(&agent, &policy, &url, None).unwrap();
+    assert_eq!(part.data, b"hello");
+    assert_eq!(retries.load(Ordering::Relaxed), 2);
+    assert_eq!(server.join().unwrap().len(), 3);
+}
+
+#[test]
+fn test_error_classification() {
+    let no_retries = RetryPolicy {
+        max_attempts: 1,
+        ..Default::default()
+    };
+    let agent = build_agent(DEFAULT_USER_AGENT, 1, Duration::from_secs(5));
+    //  Status codes
+    for (status, retryable) in [
+        (400, false),
+        (403, false),
+        (404, false),
+        (408, true),
+        (410, false),
+        (414, false),
+        (421, true),
+        (425, true),
+        (429, true),
+        (500, true),
+        (501, false),
+        (502, true),
+        (503, true),
+        (504, true),
+    ] {
+        let (url, server) = serve_responses(vec![format!(
+            "HTTP/1.1 {} Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status
+        )]);
+        match fetch_asset(&agent, &no_retries, &url, Some((0, 99))) {
+            Err(e @ ureq::Error::Status(s, _)) if s == status => assert_eq!(err_is_retryable(&e), retryable, "status {}", status),
+            r => panic!("Unexpected result for status {}: {:?}", status, r),
+        }
+        server.join().unwrap();
+    }
+    //  Not retried, even with retries allowed. The server answers only once.
+    let (url, server) = serve_responses(vec![
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    ]);
+    let retries = RetryPolicy {
+        base_delay: Duration::from_millis(10),
+        ..Default::default()
+    };
+    assert!(matches!(fetch_asset(&agent, &retries, &url, None), Err(ureq::Error::Status(404, _))));
+    assert_eq!(server.join().unwrap().len(), 1);
+    //  Transport errors
+    let closed_port = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }; // nothing listening now
+    let err = |url: &str| fetch_asset(&agent, &no_retries, url, None).expect_err(url);
+    assert!(err_is_retryable(&err(&format!("http://127.0.0.1:{}/image.j2k", closed_port)))); // connection refused
+    assert!(!err_is_retryable(&err("http://")));
+    assert!(!err_is_retryable(&err("ftp://www.example.com/image.j2k")));
+    //  DNS errors can't be made here, only their messages.
+    assert!(dns_failure_is_temporary("resolve dns name 'x.com:80' failed to lookup address information: Temporary failure in name resolution"));
+    //  Windows: WSAHOST_NOT_FOUND is permanent, WSATRY_AGAIN is temporary.
+    assert!(!dns_failure_is_temporary("failed to lookup address information: No such host is known. (os error 11001)"));
+    assert!(dns_failure_is_temporary("failed to lookup address information: This is usually a temporary error during hostname resolution and means that the local server did not receive a response from an authoritative server. (os error 11002)"));
+    assert!(!dns_failure_is_temporary("resolve dns name 'x.com:80' failed to lookup address information: Name or service not known"));
+}
+
+#[test]
+#[ignore] // depends on how the local resolver reports an unknown host
+fn test_error_classification_dns() {
+    let agent = build_agent(DEFAULT_USER_AGENT, 1, Duration::from_secs(5));
+    let no_retries = RetryPolicy {
+        max_attempts: 1,
+        ..Default::default()
+    };
+    let err = fetch_asset(&agent, &no_retries, "http://no-such-host.invalid/image.j2k", None).expect_err("no such host");
+    assert!(matches!(&err, ureq::Error::Transport(t) if t.kind() == ureq::ErrorKind::Dns));
+    assert!(!err_is_retryable(&err));
+}
+
+#[test]
+fn test_range_not_satisfiable() {
+    //  The asset is shorter than where the range starts. Fetched again, without a range.
+    let (url, server) = serve_responses(vec![
+        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */5\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello".to_string(),
+    ]);
+    let agent = build_agent(DEFAULT_USER_AGENT, 1, Duration::from_secs(5));
+    let part = fetch_asset(&agent, &RetryPolicy::default(), &url, Some((100, 199))).unwrap();
+    assert_eq!((part.data.as_slice(), part.start, part.at_end), (b"hello".as_slice(), 0, true));
+    let requests = server.join().unwrap();
+    assert!(requests[0].to_ascii_lowercase().contains("range: bytes=100-199"));
+    assert!(!requests[1].to_ascii_lowercase().contains("range:"));
+}
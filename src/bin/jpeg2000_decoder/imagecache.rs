@@ -0,0 +1,239 @@
+//! # imagecache.rs  -- decoded images, kept for repeat requests.
+//
+//  Animats
+//  March, 2023
+//
+//  In LLSD mode, the same texture is often requested over and over at the
+//  same size, because many objects share it. Decoded images from URLs are kept
+//  here, up to a memory limit, so repeat requests need neither the network
+//  nor the decoder. Least recently used images are dropped first.
+//
+//  Images are found by URL and discard level. A request by maximum size
+//  finds an image decoded earlier for the same maximum size.
+//
+//  Only textures are kept. Their URLs have a texture UUID, and they never
+//  change. Other URLs can change, and the disk cache checks with the server
+//  before reusing them, which a copy here would skip. Images known to have
+//  less detail than their discard level, from short files, are not kept either,
+//  so a later request gets another chance at the full detail. Images whose
+//  detail is not known are kept; fetching them again would tell no more.
+//
+use crate::cache::texture_id;
+use jpeg2000_decoder::protocol::{DecodeReply, DecodeRequest, StatsReply};
+use std::collections::{BTreeMap, HashMap};
+
+/// Identifies a cached image. URL and discard level.
+type ImageKey = (String, u32);
+
+/// One decoded image.
+struct CachedImage {
+    /// Maximum sizes requested which gave this discard level.
+    max_sizes: Vec<u32>,
+    /// The reply, with the pixels. Discard level is reply.discard.
+    reply: DecodeReply,
+    /// When last used, in uses of the cache. Key in `DecodedImages::by_use`.
+    last_use: u64,
+}
+
+/// Decoded images, found by URL and discard level, and dropped least recently used first.
+pub struct DecodedImages {
+    /// The images
+    images: HashMap<ImageKey, CachedImage>,
+    /// Discard level given by a URL and maximum size, for requests by size.
+    sizes: HashMap<(String, u32), u32>,
+    /// Images in order of last use, oldest first.
+    by_use: BTreeMap<u64, ImageKey>,
+    /// Uses of the cache, for `last_use`.
+    uses: u64,
+    /// Bytes of pixels held
+    bytes: usize,
+    /// Limit on bytes of pixels held. 0 means no caching.
+    max_bytes: usize,
+    /// Requests answered from the cache
+    hits: u64,
+    /// Requests not answered from the cache
+    misses: u64,
+}
+
+impl DecodedImages {
+    /// Empty, holding no more than max_bytes of pixels.
+    pub fn new(max_bytes: usize) -> DecodedImages {
+        DecodedImages {
+            images: HashMap::new(),
+            sizes: HashMap::new(),
+            by_use: BTreeMap::new(),
+            uses: 0,
+            bytes: 0,
+            max_bytes,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Key of the image a request asks for, if it could be cached.
+    fn key(&self, request: &DecodeRequest) -> Option<ImageKey> {
+        let discard = match request.max_size {
+            Some(max_size) => *self.sizes.get(&(request.url.clone(), max_size))?,
+            None => request.discard.unwrap_or(0), // no size is full size
+        };
+        Some((request.url.clone(), discard))
+    }
+
+    /// The reply for a request, if the image is cached. Counts hits and misses.
+    /// Only URL requests can be answered from the cache.
+    pub fn get(&mut self, request: &DecodeRequest) -> Option<DecodeReply> {
+        if request.data.is_some() {
+            return None;
+        }
+        let key = self.key(request);
+        self.uses += 1;
+        match key.and_then(|key| self.images.get_mut(&key).map(|image| (key, image))) {
+            Some((key, image)) => {
+                self.hits += 1;
+                //  Now most recently used.
+                self.by_use.remove(&image.last_use);
+                image.last_use = self.uses;
+                self.by_use.insert(image.last_use, key);
+                Some(DecodeReply {
+                    id: request.id,
+                    ..image.reply.clone()
+                })
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Keep the successful reply to a texture request, unless it is known to have less detail than asked for.
+    pub fn insert(&mut self, request: &DecodeRequest, reply: &DecodeReply) {
+        if request.data.is_some()
+            || texture_id(&request.url).is_none()
+            || reply.err.is_some()
+            || reply.effective_discard.is_some_and(|effective_discard| effective_discard != reply.discard)
+            || reply.image.len() > self.max_bytes
+        {
+            return;
+        }
+        //  Another maximum size may have given the same discard level.
+        let key = (request.url.clone(), reply.discard);
+        let mut max_sizes = match self.images.remove(&key) {
+            Some(old) => {
+                self.bytes -= old.reply.image.len();
+                self.by_use.remove(&old.last_use);
+                old.max_sizes
+            }
+            None => Vec::new(),
+        };
+        if let Some(max_size) = request.max_size {
+            if !max_sizes.contains(&max_size) {
+                max_sizes.push(max_size);
+            }
+            self.sizes.insert((request.url.clone(), max_size), reply.discard);
+        }
+        self.uses += 1;
+        self.bytes += reply.image.len();
+        self.by_use.insert(self.uses, key.clone());
+        self.images.insert(
+            key,
+            CachedImage {
+                max_sizes,
+                reply: reply.clone(),
+                last_use: self.uses,
+            },
+        );
+        while self.bytes > self.max_bytes {
+            match self.by_use.pop_first() {
+                Some((_, key)) => self.remove(&key),
+                None => break,
+            }
+        }
+    }
+
+    /// Drop an image, and the maximum sizes which find it.
+    fn remove(&mut self, key: &ImageKey) {
+        if let Some(image) = self.images.remove(key) {
+            self.bytes -= image.reply.image.len();
+            for max_size in image.max_sizes {
+                let size_key = (key.0.clone(), max_size);
+                if self.sizes.get(&size_key) == Some(&key.1) {
+                    self.sizes.remove(&size_key);
+                }
+            }
+        }
+    }
+
+    /// Hit and miss counts, and what is held.
+    pub fn stats(&self, id: u32) -> StatsReply {
+        let count = |n: u64| n.min(u64::from(u32::MAX)) as u32;
+        StatsReply {
+            id,
+            hits: count(self.hits),
+            misses: count(self.misses),
+            images: count(self.images.len() as u64),
+            bytes: count(self.bytes as u64),
+        }
+    }
+}
+
+#[test]
+fn test_decoded_images() {
+    const URL: &str = "http://asset.example.com/?texture_id=89556747-24cb-43ed-920b-47caed15465f";
+    let reply = |discard: u32, len: usize| DecodeReply {
+        url: URL.to_string(),
+        discard,
+        effective_discard: Some(discard),
+        image: vec![0; len],
+        ..Default::default()
+    };
+    let by_discard = |discard: u32| DecodeRequest {
+        id: 1,
+        url: URL.to_string(),
+        discard: Some(discard),
+        ..Default::default()
+    };
+    let by_size = |max_size: u32| DecodeRequest {
+        id: 2,
+        url: URL.to_string(),
+        max_size: Some(max_size),
+        ..Default::default()
+    };
+    let mut cache = DecodedImages::new(1000);
+    assert!(cache.get(&by_discard(2)).is_none());
+    cache.insert(&by_discard(2), &reply(2, 400));
+    assert_eq!(cache.get(&by_discard(2)).map(|r| (r.id, r.discard)), Some((1, 2)));
+    assert!(cache.get(&by_size(128)).is_none()); // size not seen yet
+    cache.insert(&by_size(128), &reply(2, 400)); // replaces, same discard level
+    assert_eq!(cache.get(&by_size(128)).map(|r| (r.id, r.discard)), Some((2, 2)));
+    assert!(cache.get(&by_discard(2)).is_some());
+    assert_eq!(cache.stats(7), StatsReply { id: 7, hits: 3, misses: 2, images: 1, bytes: 400 });
+    //  Errors, blurry images, data requests, URLs which are not textures,
+    //  and images bigger than the whole cache are not kept.
+    cache.insert(&by_discard(1), &DecodeReply::error(1, URL, "bad".to_string()));
+    cache.insert(&by_discard(1), &DecodeReply { effective_discard: Some(3), ..reply(1, 100) });
+    cache.insert(&by_discard(0), &reply(0, 1001));
+    let other_url = DecodeRequest {
+        url: "http://www.example.com/file.j2k".to_string(),
+        ..by_discard(4)
+    };
+    cache.insert(&other_url, &reply(4, 10));
+    assert!(cache.get(&other_url).is_none());
+    let data_request = DecodeRequest {
+        data: Some(vec![0xff, 0x4f]),
+        ..Default::default()
+    };
+    cache.insert(&data_request, &reply(3, 10));
+    assert!(cache.get(&data_request).is_none());
+    assert_eq!(cache.stats(0).images, 1);
+    //  Detail not known is kept.
+    cache.insert(&by_discard(5), &DecodeReply { effective_discard: None, ..reply(5, 1) });
+    assert!(cache.get(&by_discard(5)).is_some());
+    //  Least recently used dropped first.
+    cache.insert(&by_discard(3), &reply(3, 100));
+    assert!(cache.get(&by_discard(2)).is_some());
+    cache.insert(&by_discard(1), &reply(1, 600));
+    assert!(cache.get(&by_discard(3)).is_none());
+    assert!(cache.get(&by_discard(2)).is_some());
+    assert_eq!(cache.stats(0).bytes, 1000);
+}
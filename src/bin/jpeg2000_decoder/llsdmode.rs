@@ -0,0 +1,361 @@
+//! # llsdmode.rs  -- LLSD mode. Decode requests from standard input.
+//
+//  Animats
+//  March, 2023
+//
+//  A reader thread reads commands from standard input and puts decode requests
+//  in a priority queue. The main thread takes requests from the queue, highest
+//  priority first, and decodes them. Requests still in the queue can be
+//  cancelled or reprioritized. Replies are written to standard output as they
+//  are ready, so they may not be in request order.
+//
+//  A watchdog thread enforces request time limits. The decoder cannot be
+//  interrupted safely, so on a timeout the whole program replies and exits,
+//  and the requester starts a new one.
+//
+use crate::cache::DiskCache;
+use crate::decode::{level_text, FetchedImage, ImageSource};
+use crate::fetch::RetryPolicy;
+use crate::imagecache::DecodedImages;
+use crate::sandbox::enter_sandbox;
+use crate::stats::save_stats_file;
+use anyhow::Error;
+use jpeg2000_decoder::protocol::{read_message, write_message, Command, DecodeReply, DecodeRequest};
+use jpeg2000_decoder::RequestQueue;
+use jpeg2k::ImageFormat;
+use serde_llsd::LLSDValue;
+use std::collections::VecDeque;
+use std::sync::{Arc, Barrier, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the watchdog checks the request in progress.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(50);
+/// Exit status when a request runs out of time.
+const EXIT_TIMEOUT: i32 = 2;
+/// Limit on file bytes held for upgrading images. Decoded pixels are not held.
+const MAX_HELD_BYTES: usize = 32 * 1024 * 1024;
+
+/// State shared between the reader thread and the decoding thread.
+#[derive(Default)]
+struct InputState {
+    /// Requests waiting to be decoded
+    queue: RequestQueue,
+    /// No more input. Set at end of file, or on error.
+    done: bool,
+    /// Input error, if any
+    error: Option<Error>,
+}
+
+/// Input state, plus a signal for when it changes.
+#[derive(Default)]
+struct Input {
+    state: Mutex<InputState>,
+    changed: Condvar,
+}
+
+/// Request in progress, with a time limit.
+struct Deadline {
+    /// Request id
+    id: u32,
+    /// Request URL
+    url: String,
+    /// Reply and exit if not done by this time
+    when: Instant,
+}
+
+/// Request in progress, if it has a time limit.
+type CurrentDeadline = Arc<Mutex<Option<Deadline>>>;
+
+/// Decoded images, shared so the reader thread can report cache statistics.
+type SharedDecodedImages = Arc<Mutex<DecodedImages>>;
+
+/// Send one reply. Standard output is locked for the whole message,
+/// because both threads send replies.
+fn send_reply(reply: &DecodeReply) -> Result<(), Error> {
+    write_message(&mut std::io::stdout().lock(), &reply.to_llsd())
+}
+
+/// LLSD mode.
+///
+/// Reads framed LLSD commands from standard input, and writes one LLSD reply
+/// for each decode request to standard output, until end of file on input.
+/// Errors on individual images are returned in the reply, and do not stop the program.
+///
+/// In sandbox mode, the sandbox is entered once the threads are running,
+/// and requests with a URL instead of data are refused.
+/// Fetches are retried as the retry policy says, and go through the disk cache, if there is one.
+/// Decoded images are kept in memory, up to image_cache_bytes, for repeat requests.
+pub fn run_llsd_mode(
+    agent: &ureq::Agent,
+    retry_policy: &RetryPolicy,
+    cache_opt: Option<&DiskCache>,
+    image_cache_bytes: usize,
+    sandbox: bool,
+) -> Result<(), Error> {
+    let input = Arc::new(Input::default());
+    let decoded: SharedDecodedImages = Arc::new(Mutex::new(DecodedImages::new(image_cache_bytes)));
+    //  All threads must be running before the sandbox is entered.
+    let started = Arc::new(Barrier::new(3));
+    {
+        let input = Arc::clone(&input);
+        let decoded = Arc::clone(&decoded);
+        let started = Arc::clone(&started);
+        std::thread::spawn(move || {
+            started.wait();
+            read_commands(&input, &decoded)
+        });
+    }
+    let deadline: CurrentDeadline = Arc::new(Mutex::new(None));
+    {
+        let deadline = Arc::clone(&deadline);
+        let input = Arc::clone(&input);
+        let started = Arc::clone(&started);
+        std::thread::spawn(move || {
+            started.wait();
+            watchdog(&deadline, &input)
+        });
+    }
+    started.wait();
+    let mut held = HeldImages::default();
+    if sandbox {
+        enter_sandbox()?;
+        log::info!("Sandbox mode.");
+    }
+    loop {
+        let request = {
+            let mut state = input.state.lock().unwrap();
+            loop {
+                if let Some(request) = state.queue.pop() {
+                    break request;
+                }
+                if state.done {
+                    return match state.error.take() {
+                        Some(e) => Err(e),
+                        None => Ok(()), // normal end of file
+                    };
+                }
+                state = input.changed.wait(state).unwrap();
+            }
+        };
+        *deadline.lock().unwrap() = request.timeout_ms.map(|ms| Deadline {
+            id: request.id,
+            url: request.url.clone(),
+            when: Instant::now() + Duration::from_millis(ms.into()),
+        });
+        let mut reply = if sandbox && request.data.is_none() {
+            DecodeReply::error(request.id, &request.url, "Network not available in sandbox mode".to_string())
+        } else {
+            decode_request(agent, retry_policy, cache_opt, &request, &mut held, &decoded)
+        };
+        reply.cache_stats = Some(decoded.lock().unwrap().stats(request.id));
+        //  Once the deadline is cleared, the watchdog cannot send a reply for this request.
+        *deadline.lock().unwrap() = None;
+        send_reply(&reply)?;
+        if let Err(e) = save_stats_file(false) {
+            log::warn!("Unable to save statistics file: {}", e);
+        }
+    }
+}
+
+/// Watchdog thread. If the request in progress runs over its time limit,
+/// reply with a timeout error and exit. Requests still waiting get an error reply first,
+/// so the requester is not left waiting for them.
+fn watchdog(deadline: &CurrentDeadline, input: &Input) {
+    loop {
+        std::thread::sleep(WATCHDOG_INTERVAL);
+        //  Lock is held through exit, so the main thread cannot also reply.
+        let current = deadline.lock().unwrap();
+        if let Some(current) = &*current {
+            if Instant::now() >= current.when {
+                log::error!(step = "timeout", id = current.id, url = current.url.as_str(); "Timeout on #{} {}, exiting.", current.id, current.url);
+                let _ = send_reply(&DecodeReply::timed_out(current.id, &current.url));
+                //  This lock is also held through exit, so no more requests are queued.
+                let mut state = input.state.lock().unwrap();
+                while let Some(request) = state.queue.pop() {
+                    let msg = format!("Decoder exited after timeout of #{}, before starting this request", current.id);
+                    let _ = send_reply(&DecodeReply::error(request.id, &request.url, msg));
+                }
+                let _ = save_stats_file(true); // keep what was learned
+                std::process::exit(EXIT_TIMEOUT);
+            }
+        }
+    }
+}
+
+/// Reader thread. Reads commands until end of file or error.
+fn read_commands(input: &Input, decoded: &SharedDecodedImages) {
+    let status = read_commands_until_done(input, decoded);
+    let mut state = input.state.lock().unwrap();
+    state.done = true;
+    state.error = status.err();
+    input.changed.notify_all();
+}
+
+/// Read and act on commands.
+fn read_commands_until_done(input: &Input, decoded: &SharedDecodedImages) -> Result<(), Error> {
+    let stdin = std::io::stdin();
+    let mut reader = stdin.lock();
+    while let Some(msg) = read_message(&mut reader)? {
+        match Command::from_llsd(&msg) {
+            Ok(Command::Decode(request)) => {
+                //  Ids of waiting requests must be unique, or cancel and priority would not know which one was meant.
+                let mut state = input.state.lock().unwrap();
+                if state.queue.contains(request.id) {
+                    drop(state);
+                    let msg = format!("Bad request: request #{} is already waiting", request.id);
+                    send_reply(&DecodeReply::error(request.id, &request.url, msg))?;
+                } else {
+                    state.queue.push(request);
+                    input.changed.notify_all();
+                }
+            }
+            Ok(Command::Cancel(id)) => {
+                //  The cancelled request still gets a reply, so the requester is not left waiting.
+                let cancelled = input.state.lock().unwrap().queue.cancel(id);
+                if let Some(request) = cancelled {
+                    log::info!(step = "cancel", id = request.id, url = request.url.as_str(); "Cancelled #{} {}", request.id, request.url);
+                    send_reply(&DecodeReply::cancelled(request.id, &request.url))?;
+                }
+            }
+            Ok(Command::SetPriority(id, priority)) => {
+                input.state.lock().unwrap().queue.set_priority(id, priority);
+            }
+            Ok(Command::Stats(id)) => {
+                let stats = decoded.lock().unwrap().stats(id);
+                log::info!(step = "stats", hits = stats.hits, misses = stats.misses, images = stats.images, bytes = stats.bytes;
+                    "Decoded image cache: {} hits, {} misses, {} images, {} bytes", stats.hits, stats.misses, stats.images, stats.bytes);
+                write_message(&mut std::io::stdout().lock(), &stats.to_llsd())?;
+            }
+            Err(e) => {
+                //  Bad command. Reply with error, and echo the id and URL if there are any.
+                let id = match msg.get("id") {
+                    Some(LLSDValue::Integer(id)) => (*id).max(0) as u32,
+                    _ => 0,
+                };
+                let url = match msg.get("url") {
+                    Some(LLSDValue::String(s)) => s.as_str(),
+                    _ => "",
+                };
+                send_reply(&DecodeReply::error(id, url, format!("Bad request: {}", e)))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Images decoded at reduced resolution from URLs, kept so they can be upgraded.
+/// Only the file bytes are kept, up to MAX_HELD_BYTES. Oldest dropped first.
+#[derive(Default)]
+struct HeldImages {
+    images: VecDeque<(String, FetchedImage)>,
+    /// File bytes held, for all the images.
+    bytes: usize,
+}
+
+impl HeldImages {
+    /// Take the image for a URL, if held.
+    fn take(&mut self, url: &str) -> Option<FetchedImage> {
+        let pos = self.images.iter().position(|(held_url, _)| held_url == url)?;
+        let (_, image) = self.images.remove(pos)?;
+        self.bytes -= image.held_bytes();
+        Some(image)
+    }
+
+    /// Keep an image for upgrading, without its pixels. Complete images cannot be upgraded, and are not kept.
+    fn hold(&mut self, url: String, mut image: FetchedImage) {
+        if image.is_complete() || image.held_bytes() > MAX_HELD_BYTES {
+            return;
+        }
+        image.release_pixels();
+        self.bytes += image.held_bytes();
+        self.images.push_back((url, image));
+        while self.bytes > MAX_HELD_BYTES {
+            match self.images.pop_front() {
+                Some((_, image)) => self.bytes -= image.held_bytes(),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Handle one LLSD mode request.
+fn decode_request(
+    agent: &ureq::Agent,
+    retry_policy: &RetryPolicy,
+    cache_opt: Option<&DiskCache>,
+    request: &DecodeRequest,
+    held: &mut HeldImages,
+    decoded: &Mutex<DecodedImages>,
+) -> DecodeReply {
+    let now = Instant::now();
+    //  Repeat requests are answered from the decoded image cache.
+    if let Some(reply) = decoded.lock().unwrap().get(request) {
+        log::info!(
+            step = "reply", id = request.id, url = request.url.as_str(), width = reply.w, height = reply.h, depth = reply.d,
+            discard = reply.discard, effective_discard = reply.effective_discard, cached = true, elapsed_us = now.elapsed().as_micros() as u64;
+            "Cached #{} {}: ({}, {}, {}), discard level {}, effective {}",
+            request.id, request.url, reply.w, reply.h, reply.d, reply.discard, level_text(reply.effective_discard)
+        );
+        return reply;
+    }
+    //  Any held image for this URL is replaced by this request's image.
+    let mut image = match held.take(&request.url) {
+        Some(image) if request.upgrade => image,
+        _ => FetchedImage::default(),
+    };
+    let result = match &request.data {
+        Some(data) => image.decode_to_size(data, request.max_size, request.discard),
+        None if image.is_loaded() => image
+            .upgrade(&ImageSource::url(agent, retry_policy, cache_opt, &request.url), request.max_size, request.discard)
+            .map(|_| ()),
+        None => image.fetch_to_size(agent, retry_policy, cache_opt, &request.url, request.max_size, request.discard),
+    }
+    .and_then(|_| Ok((image.get_pixels()?, image.get_discard_level(), image.get_effective_discard_level())));
+    if result.is_ok() && request.data.is_none() {
+        held.hold(request.url.clone(), image);
+    }
+    let reply = match result {
+        Ok((pixels, discard, effective_discard)) => {
+            let d = match pixels.format {
+                ImageFormat::L8 => 1,
+                ImageFormat::La8 => 2,
+                ImageFormat::Rgb8 => 3,
+                ImageFormat::Rgba8 => 4,
+            };
+            log::info!(
+                step = "reply", id = request.id, url = request.url.as_str(), width = pixels.width, height = pixels.height,
+                depth = d, discard = discard, effective_discard = effective_discard, elapsed_us = now.elapsed().as_micros() as u64;
+                "Decoded #{} {}: ({}, {}, {}), discard level {}, effective {}, in {:#?}",
+                request.id,
+                request.url,
+                pixels.width,
+                pixels.height,
+                d,
+                discard,
+                level_text(effective_discard),
+                now.elapsed()
+            );
+            DecodeReply {
+                id: request.id,
+                url: request.url.clone(),
+                err: None,
+                cancelled: false,
+                timed_out: false,
+                discard,
+                effective_discard,
+                h: pixels.height,
+                w: pixels.width,
+                d,
+                image: pixels.data,
+                cache_stats: None, // added when sent
+            }
+        }
+        Err(e) => {
+            log::info!(step = "reply", id = request.id, url = request.url.as_str(), err = e.to_string(), elapsed_us = now.elapsed().as_micros() as u64;
+                "Error decoding #{} {}: {}", request.id, request.url, e);
+            DecodeReply::error(request.id, &request.url, e.to_string())
+        }
+    };
+    decoded.lock().unwrap().insert(request, &reply);
+    reply
+}
@@ -0,0 +1,150 @@
+//! # logger.rs  -- log output, to standard error.
+//
+//  Animats
+//  March, 2023
+//
+//  Standard output carries the replies in LLSD mode, so log
+//  output always goes to standard error.
+//
+//  Each step of handling an image is logged at debug level, with
+//  a "step" key and the step's values as key-value pairs.
+//  Output is either text, or JSON, one object per line, for log collectors.
+//
+use log::kv::{Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Logger which writes one line per record to standard error.
+struct StderrLogger {
+    /// Write JSON lines instead of text.
+    json: AtomicBool,
+}
+
+/// The logger.
+static LOGGER: StderrLogger = StderrLogger {
+    json: AtomicBool::new(false),
+};
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = if self.json.load(Ordering::Relaxed) {
+            json_line(record)
+        } else {
+            let mut line = format!("{} {}", record.level(), record.args());
+            let _ = record.key_values().visit(&mut KeyValueText(&mut line));
+            line
+        };
+        eprintln!("{}", line); // one write per line, so lines from different threads do not mix
+    }
+
+    fn flush(&self) {}
+}
+
+/// Appends key-value pairs to a line as " key=value".
+struct KeyValueText<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for KeyValueText<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let _ = write!(self.0, " {}={}", key, value);
+        Ok(())
+    }
+}
+
+/// Appends key-value pairs to a JSON object as ,"key":value.
+struct KeyValueJson<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for KeyValueJson<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let _ = write!(self.0, ",{}:{}", json_string(key.as_str()), json_value(&value));
+        Ok(())
+    }
+}
+
+/// One log record as a JSON object. Time is seconds since the Unix epoch.
+fn json_line(record: &Record) -> String {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |t| t.as_secs_f64());
+    let mut line = format!(
+        "{{\"time\":{:.6},\"level\":\"{}\",\"msg\":{}",
+        time,
+        record.level(),
+        json_string(&record.args().to_string())
+    );
+    let _ = record.key_values().visit(&mut KeyValueJson(&mut line));
+    line.push('}');
+    line
+}
+
+/// A value as JSON. Numbers and booleans stay as they are, anything else becomes a string.
+fn json_value(value: &Value) -> String {
+    if let Some(v) = value.to_bool() {
+        v.to_string()
+    } else if let Some(v) = value.to_u64() {
+        v.to_string()
+    } else if let Some(v) = value.to_i64() {
+        v.to_string()
+    } else if let Some(v) = value.to_f64().filter(|v| v.is_finite()) {
+        v.to_string()
+    } else {
+        json_string(&value.to_string())
+    }
+}
+
+/// A string as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Start logging. Verbose mode logs each step. Otherwise, only warnings and errors.
+/// JSON mode writes JSON lines instead of text.
+pub fn init(verbose: bool, json: bool) {
+    LOGGER.json.store(json, Ordering::Relaxed);
+    let _ = log::set_logger(&LOGGER); // can only fail if already set
+    log::set_max_level(if verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Warn
+    });
+}
+
+#[test]
+fn test_json_line() {
+    assert_eq!(json_string("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+    let kvs: &[(&str, Value)] = &[
+        ("step", Value::from("read")),
+        ("bytes", Value::from(1024_usize)),
+        ("at_end", Value::from(true)),
+        ("ratio", Value::from(0.5_f64)),
+    ];
+    let args = format_args!("Read \"x\"");
+    let record = Record::builder().args(args).level(log::Level::Debug).key_values(&kvs).build();
+    let line = json_line(&record);
+    assert!(line.starts_with("{\"time\":"));
+    assert!(line.ends_with(
+        ",\"level\":\"DEBUG\",\"msg\":\"Read \\\"x\\\"\",\"step\":\"read\",\"bytes\":1024,\"at_end\":true,\"ratio\":0.5}"
+    ));
+}
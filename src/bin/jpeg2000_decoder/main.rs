@@ -4,19 +4,26 @@
 //  April, 2021
 //
 
-////use url::Url;
-////use std::path::Path;
-use anyhow::Error;
-use image::DynamicImage;
+use anyhow::{anyhow, Error};
 use image::GenericImageView;
-use jpeg2k::*;
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
 
+mod cache;
 mod decode;
 pub mod fetch;
-use decode::estimate_initial_read_size;
+mod imagecache;
+mod llsdmode;
+mod logger;
+mod sandbox;
+mod stats;
+use cache::DiskCache;
+use decode::{level_text, FetchedImage, ImageSource};
+use fetch::{build_agent, RetryPolicy, DEFAULT_NETWORK_TIMEOUT, DEFAULT_USER_AGENT};
+use jpeg2000_decoder::ResourceLimits;
+use llsdmode::run_llsd_mode;
+use stats::{open_stats_file, save_stats_file, COMPRESSION_STATS};
 
 /// Arguments to the program
 #[derive(Clone, Debug, Default)]
@@ -25,18 +32,49 @@ struct ArgInfo {
     pub in_url: String,
     /// Destination file
     pub out_file: String,
-    /// Maximum output image dimension, in pixels
-    pub max_size: usize,
-    /// Reduction factor
+    /// Maximum output image dimension, in pixels. 0 means full size.
+    pub max_size: u32,
+    /// Reduction factor, as a discard level. 0 means full size.
     pub reduction_factor: u8,
     /// If true, ignore above fields and read LLSD commands from input.
     pub llsd_mode: bool,
-    /// Verbose mode. Goes to standard error if LLSD mode.
+    /// Verbose mode. Goes to standard error.
     pub verbose: bool,
-    /// User agent for HTTP requests.
+    /// Log as JSON lines, instead of text.
+    pub json_log: bool,
+    /// User agent for HTTP requests. Default if empty.
     pub user_agent: String,
+    /// Connections to keep open to each server.
+    pub max_connections: usize,
+    /// Network timeout, seconds.
+    pub timeout: u64,
+    /// Retries of a failed fetch.
+    pub retries: u32,
+    /// Wait before the first retry, milliseconds. Doubles for each retry after that.
+    pub retry_delay: u64,
+    /// Longest wait before a retry, seconds.
+    pub retry_max_delay: u64,
+    /// Memory limit for this process, megabytes. 0 means no limit.
+    pub mem_limit: u64,
+    /// CPU time limit for this process, seconds. 0 means no limit.
+    pub cpu_limit: u64,
+    /// If true, LLSD mode runs in a seccomp sandbox, with no network or file access.
+    pub sandbox: bool,
+    /// File of learned compression statistics, loaded at start and added to as we go. None if empty.
+    pub stats_file: String,
+    /// Directory for the disk cache of fetched bytes. No cache if empty.
+    pub cache_dir: String,
+    /// Size limit for the disk cache, megabytes.
+    pub cache_size: u64,
+    /// Size limit for decoded images kept in memory in LLSD mode, megabytes. 0 means none are kept.
+    pub image_cache_size: u64,
 }
 
+/// Default size limit for the disk cache, megabytes.
+const DEFAULT_CACHE_SIZE: u64 = 256;
+/// Default size limit for decoded images kept in memory, megabytes.
+const DEFAULT_IMAGE_CACHE_SIZE: u64 = 64;
+
 //
 //  parseargs -- parse command line args
 //
@@ -44,12 +82,18 @@ struct ArgInfo {
 //
 fn parseargs() -> ArgInfo {
     let mut arginfo = ArgInfo {
-        max_size: 1000000000,
+        max_connections: 1,
+        timeout: DEFAULT_NETWORK_TIMEOUT.as_secs(),
+        retries: RetryPolicy::default().max_attempts - 1,
+        retry_delay: RetryPolicy::default().base_delay.as_millis() as u64,
+        retry_max_delay: RetryPolicy::default().max_delay.as_secs(),
+        cache_size: DEFAULT_CACHE_SIZE,
+        image_cache_size: DEFAULT_IMAGE_CACHE_SIZE,
         ..Default::default()
     };
     {
         //  This block limits scope of borrows by ap.refer() method
-        use argparse::{ArgumentParser, Store}; // only visible here
+        use argparse::{ArgumentParser, Store, StoreTrue}; // only visible here
         let mut ap = ArgumentParser::new();
         ap.set_description("Decoder for JPEG 2000 files.");
         ap.refer(&mut arginfo.in_url)
@@ -64,96 +108,263 @@ fn parseargs() -> ArgInfo {
             "Maximum dimension of output image",
         );
         ap.refer(&mut arginfo.verbose)
-            .add_option(&["-v", "--verbose"], Store, "Verbose mode.");
+            .add_option(&["-v", "--verbose"], StoreTrue, "Verbose mode.");
+        ap.refer(&mut arginfo.json_log).add_option(
+            &["--json-log"],
+            StoreTrue,
+            "Log as JSON lines, to standard error.",
+        );
         ap.refer(&mut arginfo.llsd_mode)
-            .add_option(&["--llsd"], Store, "LLSD mode");
+            .add_option(&["--llsd"], StoreTrue, "LLSD mode");
+        ap.refer(&mut arginfo.user_agent).add_option(
+            &["--user-agent"],
+            Store,
+            "User agent for HTTP requests.",
+        );
+        ap.refer(&mut arginfo.max_connections).add_option(
+            &["--max-connections"],
+            Store,
+            "Connections to keep open to each server.",
+        );
+        ap.refer(&mut arginfo.timeout).add_option(
+            &["--timeout"],
+            Store,
+            "Network timeout, seconds.",
+        );
+        ap.refer(&mut arginfo.retries).add_option(
+            &["--retries"],
+            Store,
+            "Retries of a failed fetch.",
+        );
+        ap.refer(&mut arginfo.retry_delay).add_option(
+            &["--retry-delay"],
+            Store,
+            "Wait before the first retry, milliseconds. Doubles for each retry after that.",
+        );
+        ap.refer(&mut arginfo.retry_max_delay).add_option(
+            &["--retry-max-delay"],
+            Store,
+            "Longest wait before a retry, seconds.",
+        );
+        ap.refer(&mut arginfo.mem_limit).add_option(
+            &["--mem-limit"],
+            Store,
+            "Memory limit, megabytes.",
+        );
+        ap.refer(&mut arginfo.cpu_limit).add_option(
+            &["--cpu-limit"],
+            Store,
+            "CPU time limit, seconds.",
+        );
+        ap.refer(&mut arginfo.sandbox).add_option(
+            &["--sandbox"],
+            StoreTrue,
+            "Sandbox mode. LLSD mode only. Linux only.",
+        );
+        ap.refer(&mut arginfo.stats_file).add_option(
+            &["--stats-file"],
+            Store,
+            "File of learned compression statistics. Loaded at start, added to as the program runs.",
+        );
+        ap.refer(&mut arginfo.cache_dir).add_option(
+            &["--cache-dir"],
+            Store,
+            "Directory for a disk cache of fetched image bytes.",
+        );
+        ap.refer(&mut arginfo.cache_size).add_option(
+            &["--cache-size"],
+            Store,
+            "Size limit for the disk cache, megabytes.",
+        );
+        ap.refer(&mut arginfo.image_cache_size).add_option(
+            &["--image-cache-size"],
+            Store,
+            "Size limit for decoded images kept in memory, megabytes. LLSD mode only.",
+        );
         ap.parse_args_or_exit();
     }
     //  Check for required args
+    if arginfo.sandbox && !arginfo.stats_file.is_empty() {
+        eprintln!("The statistics file cannot be saved in sandbox mode");
+        std::process::exit(1);
+    }
+    if arginfo.sandbox && !arginfo.cache_dir.is_empty() {
+        eprintln!("The disk cache cannot be used in sandbox mode");
+        std::process::exit(1);
+    }
     if !arginfo.llsd_mode {
         if arginfo.in_url.is_empty() || arginfo.out_file.is_empty() {
             eprintln!("If LLSD mode is off, an input URL and an output file must be specified");
             std::process::exit(1);
         }
+        if arginfo.max_size > 0 && arginfo.reduction_factor > 0 {
+            eprintln!("Specify either a maximum size or a reduction factor, not both");
+            std::process::exit(1);
+        }
+        if arginfo.sandbox {
+            eprintln!("Sandbox mode is only available in LLSD mode");
+            std::process::exit(1);
+        }
     }
     arginfo
 }
 
-/// LLSD mode
-fn run_llsd_mode(verbose: bool) -> Result<(), Error> {
-    todo!()
+/// Where to get the input. A URL or a file.
+///
+/// HTTP and HTTPS URLs are fetched from the server, through the disk cache if there is one.
+/// "file:" URLs and plain paths are local files.
+fn input_source<'a>(
+    agent: &'a ureq::Agent,
+    retry_policy: &'a RetryPolicy,
+    cache_opt: Option<&'a DiskCache>,
+    in_url: &'a str,
+) -> Result<ImageSource<'a>, Error> {
+    match Url::parse(in_url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            Ok(ImageSource::url(agent, retry_policy, cache_opt, in_url))
+        }
+        Ok(url) if url.scheme() == "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|_| anyhow!("Not a local file URL: {}", in_url))?;
+            Ok(ImageSource::File(path))
+        }
+        //  One letter schemes are Windows drive letters, not URLs.
+        Ok(url) if url.scheme().len() > 1 => Err(anyhow!(
+            "Unsupported URL scheme \"{}\" in {}",
+            url.scheme(),
+            in_url
+        )),
+        _ => Ok(ImageSource::File(PathBuf::from(in_url))), // plain file name
+    }
 }
 
 /// Decompress one URL or file mode.
+///
+/// Only the part of the file needed for the requested size is read.
 fn decompress_one_url(
+    agent: &ureq::Agent,
+    retry_policy: &RetryPolicy,
+    cache_opt: Option<&DiskCache>,
     in_url: &str,
     out_file: &str,
-    max_size: usize,
-    reduction: u8,
-    verbose: bool,
+    max_size_opt: Option<u32>,
+    discard_opt: Option<u32>,
 ) -> Result<(), Error> {
-    // Initial dumb version.
-    let file_bytes_guess = max_size * max_size * 4 + 200; // guess file size needed.
-    let in_file = File::open(in_url)?;
-    let mut buf_reader = BufReader::new(in_file);
-    let mut contents = Vec::new();
-    buf_reader.read_to_end(&mut contents)?;
-    let contents = if contents.len() > file_bytes_guess {
-        println!(
-            "Truncating file from {} bytes to {} bytes",
-            contents.len(),
-            file_bytes_guess
-        );
-        contents[0..file_bytes_guess].to_vec()
-    } else {
-        contents
-    };
-    let decode_parameters = DecodeParameters::new().reduce(reduction.into());
-    ////println!("Decode parameters: {:?}", decode_parameters);
-    let jp2_image = Image::from_bytes_with(&contents, decode_parameters)?;
-    println!("Input file {}: {:?}", in_url, jp2_image);
-    ////let jp2_image = Image::from_file(in_url)?; // load from file (not URL)
-    /*
-        //  ***TEMP*** timing test - result is about 30ms per image.
-        let now = std::time::Instant::now();
-        const TRIES: usize = 1000;
-        for _ in 0..1000 {
-            let decode_parameters = DecodeParameters::new();
-            let jp2_image = Image::from_bytes_with(&contents, decode_parameters)?;
-            let img: DynamicImage = (&jp2_image).try_into()?;  // convert
-        }
-        let elapsed = now.elapsed().as_secs_f32() / (TRIES as f32);
-        println!("Decompression time: {} secs.", elapsed);
-    */
-
-    let img: DynamicImage = (&jp2_image).try_into()?; // convert
+    let source = input_source(agent, retry_policy, cache_opt, in_url)?;
+    let mut image = FetchedImage::default();
+    image.load_to_size(&source, max_size_opt, discard_opt)?;
+    let img = image.get_dynamic_image()?;
+    log::info!(
+        "Input file {}: discard level {}, effective discard level {}",
+        in_url,
+        image.get_discard_level(),
+        level_text(image.get_effective_discard_level())
+    );
     println!(
         "Output file {}: ({}, {})",
         out_file,
         img.width(),
         img.height()
     );
+    let now = std::time::Instant::now();
     img.save(out_file)?; // save as PNG file
+    log::debug!(step = "encode", width = img.width(), height = img.height(), elapsed_us = now.elapsed().as_micros() as u64;
+        "Saved {} in {:#?}", out_file, now.elapsed());
     Ok(())
 }
 
 /// Main program
 fn main() {
     let args = parseargs();
-    eprintln!("args: {:?}", args); // ***TEMP***
+    logger::init(args.verbose, args.json_log);
+    log::debug!("Arguments: {:?}", args);
+    //  Limit our own resources before touching any image data.
+    let limits = ResourceLimits {
+        memory_bytes: Some(args.mem_limit.saturating_mul(1024 * 1024)).filter(|v| *v > 0),
+        cpu_seconds: Some(args.cpu_limit).filter(|v| *v > 0),
+    };
+    if let Err(e) = limits.apply() {
+        log::error!("Unable to set resource limits: {:?}", e);
+        std::process::exit(1);
+    }
+    //  Start with what earlier runs learned.
+    if !args.stats_file.is_empty() {
+        if let Err(e) = open_stats_file(std::path::Path::new(&args.stats_file)) {
+            log::error!("Unable to load statistics file {}: {:?}", args.stats_file, e);
+            std::process::exit(1);
+        }
+    }
+    let cache_opt = if args.cache_dir.is_empty() {
+        None
+    } else {
+        match DiskCache::new(std::path::Path::new(&args.cache_dir), args.cache_size.saturating_mul(1024 * 1024)) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                log::error!("Unable to use cache directory {}: {:?}", args.cache_dir, e);
+                std::process::exit(1);
+            }
+        }
+    };
+    //  One agent for all requests, so connections are reused.
+    let user_agent = if args.user_agent.is_empty() {
+        DEFAULT_USER_AGENT
+    } else {
+        args.user_agent.as_str()
+    };
+    let agent = build_agent(
+        user_agent,
+        args.max_connections.max(1),
+        Duration::from_secs(args.timeout.max(1)),
+    );
+    let retry_policy = RetryPolicy {
+        max_attempts: args.retries.saturating_add(1),
+        base_delay: Duration::from_millis(args.retry_delay),
+        max_delay: Duration::from_secs(args.retry_max_delay),
+        ..Default::default()
+    };
     let status = if args.llsd_mode {
-        run_llsd_mode(args.verbose)
+        run_llsd_mode(
+            &agent,
+            &retry_policy,
+            cache_opt.as_ref(),
+            usize::try_from(args.image_cache_size.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX),
+            args.sandbox,
+        )
     } else {
         decompress_one_url(
+            &agent,
+            &retry_policy,
+            cache_opt.as_ref(),
             args.in_url.as_str(),
-            args.out_file.as_str(),           
-            args.max_size,
-            args.reduction_factor,
-            args.verbose,
+            args.out_file.as_str(),
+            Some(args.max_size).filter(|v| *v > 0),
+            Some(args.reduction_factor.into()).filter(|v| *v > 0),
         )
     };
+    //  Report and keep what was learned.
+    log::info!("Compression statistics:\n{}", COMPRESSION_STATS.lock().unwrap().report().trim_end());
+    if let Err(e) = save_stats_file(true) {
+        log::error!("Unable to save statistics file {}: {:?}", args.stats_file, e);
+    }
     if let Err(e) = status {
-        eprintln!("Error: {:?}", e);
+        log::error!("Error: {:?}", e);
         std::process::exit(1);
     }
 }
+
+#[test]
+fn test_input_source() {
+    let agent = build_agent(DEFAULT_USER_AGENT, 1, DEFAULT_NETWORK_TIMEOUT);
+    let retry_policy = RetryPolicy::default();
+    let path = std::env::temp_dir().join("file.j2k");
+    let file_url = Url::from_file_path(&path).unwrap();
+    let cache_dir = std::env::temp_dir().join(format!("jpeg2000-decoder-source-{}", std::process::id()));
+    let cache = DiskCache::new(&cache_dir, 0).unwrap();
+    assert!(matches!(input_source(&agent, &retry_policy, None, "http://www.example.com/file.j2k"), Ok(ImageSource::Url(_, _, _))));
+    assert!(matches!(input_source(&agent, &retry_policy, Some(&cache), "http://www.example.com/file.j2k"), Ok(ImageSource::CachedUrl(_, _, _, _))));
+    assert!(matches!(input_source(&agent, &retry_policy, Some(&cache), file_url.as_str()), Ok(ImageSource::File(p)) if p == path));
+    assert!(matches!(input_source(&agent, &retry_policy, None, "samples/file.j2k"), Ok(ImageSource::File(_))));
+    assert!(input_source(&agent, &retry_policy, None, "ftp://www.example.com/file.j2k").is_err()); // unsupported
+    std::fs::remove_dir_all(&cache_dir).unwrap();
+}
@@ -0,0 +1,185 @@
+//! # sandbox.rs  -- seccomp sandbox for LLSD mode.
+//
+//  Animats
+//  March, 2023
+//
+//  Once the sandbox is entered, the only system calls allowed are
+//  reading standard input, writing standard output and standard error,
+//  memory allocation, thread synchronization, time, and exit.
+//  Any other system call kills the process. So even if a bad image
+//  takes over the decoder, it cannot open files or network connections.
+//
+//  Linux only, x86_64 and aarch64.
+//
+use anyhow::Error;
+
+/// Enter the sandbox. Applies to all threads of the process, and cannot be undone.
+///
+/// Everything needing other system calls, such as starting threads
+/// and opening network connections, must be done before this.
+/// Threads must have finished starting up, too, because thread
+/// startup makes system calls not allowed here.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn enter_sandbox() -> Result<(), Error> {
+    let filter = build_filter();
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    //  Safety: plain system calls. The kernel copies the filter program.
+    unsafe {
+        //  Required to install a filter without privileges. Also blocks setuid programs.
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        //  TSYNC applies the filter to the threads already running, too.
+        if libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const libc::sock_fprog,
+        ) != 0
+        {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+/// Enter the sandbox. Not available on this platform.
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn enter_sandbox() -> Result<(), Error> {
+    Err(anyhow::anyhow!("Sandbox mode is only available on Linux, x86_64 and aarch64"))
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod filter {
+    //  Architecture codes, from linux/audit.h
+    #[cfg(target_arch = "x86_64")]
+    pub const AUDIT_ARCH: u32 = 0xC000_003E; // AUDIT_ARCH_X86_64
+    #[cfg(target_arch = "aarch64")]
+    pub const AUDIT_ARCH: u32 = 0xC000_00B7; // AUDIT_ARCH_AARCH64
+    /// x32 system calls have this bit set in the number. Not used by us, refused.
+    #[cfg(target_arch = "x86_64")]
+    pub const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    //  Offsets into struct seccomp_data
+    pub const OFFSET_NR: u32 = 0;
+    pub const OFFSET_ARCH: u32 = 4;
+    /// Low 32 bits of argument n are at OFFSET_ARGS + 8 * n. Little-endian only.
+    pub const OFFSET_ARGS: u32 = 16;
+
+    /// System calls allowed with any arguments.
+    pub const ALLOWED: &[libc::c_long] = &[
+        //  Memory allocation
+        libc::SYS_brk,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_madvise,
+        //  Thread synchronization
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        //  Time, for the watchdog
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        //  Hash table seeds
+        libc::SYS_getrandom,
+        //  Signal return and thread cleanup
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_sigaltstack,
+        //  Exit
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+
+    /// System calls allowed only if they do not make memory executable. Protection is argument 2.
+    pub const ALLOWED_NO_EXEC: &[libc::c_long] = &[libc::SYS_mmap, libc::SYS_mprotect];
+}
+
+/// Build the BPF filter program.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn build_filter() -> Vec<libc::sock_filter> {
+    use filter::*;
+    use libc::{
+        BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K, BPF_LD, BPF_RET, BPF_W, SECCOMP_RET_ALLOW,
+        SECCOMP_RET_KILL_PROCESS,
+    };
+    //  BPF instruction builders. Jump offsets are relative to the next instruction.
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load = |offset: u32| stmt(BPF_LD | BPF_W | BPF_ABS, offset);
+    let allow = stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW);
+    let kill = stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS);
+
+    let mut prog = vec![
+        //  Wrong architecture means system call numbers mean something else.
+        load(OFFSET_ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        kill,
+        load(OFFSET_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    prog.extend([
+        jump(BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+        kill,
+    ]);
+    for nr in ALLOWED {
+        prog.extend([jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, 0, 1), allow]);
+    }
+    for nr in ALLOWED_NO_EXEC {
+        prog.extend([
+            jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, 0, 4),
+            load(OFFSET_ARGS + 8 * 2),
+            jump(BPF_JMP | BPF_JSET | BPF_K, libc::PROT_EXEC as u32, 1, 0),
+            allow,
+            kill,
+        ]);
+    }
+    //  read, standard input only.
+    prog.extend([
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_read as u32, 0, 4),
+        load(OFFSET_ARGS),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 0, 1),
+        allow,
+        kill,
+    ]);
+    //  write, standard output and standard error only.
+    prog.extend([
+        jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_write as u32, 0, 5),
+        load(OFFSET_ARGS),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, 1, 2, 0),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, 2, 1, 0),
+        kill,
+        allow,
+    ]);
+    prog.push(kill); // everything else
+    prog
+}
+
+#[test]
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+/// Check that all jumps in the filter land inside the program.
+/// The filter itself cannot be tested here, because it would apply to the test program.
+fn test_sandbox_filter_jumps() {
+    let prog = build_filter();
+    assert!(prog.len() < u16::MAX as usize);
+    for (i, insn) in prog.iter().enumerate() {
+        if insn.code as u32 & 0x07 == libc::BPF_JMP {
+            assert!(i + 1 + (insn.jt as usize) < prog.len());
+            assert!(i + 1 + (insn.jf as usize) < prog.len());
+        }
+    }
+    assert_eq!(prog.last().unwrap().code as u32, libc::BPF_RET | libc::BPF_K); // ends with a return
+}
@@ -0,0 +1,373 @@
+//! # stats.rs  -- learned compression ratios, for sizing reads.
+//
+//  Animats
+//  March, 2023
+//
+//  How many bytes of a JPEG 2000 file are needed per output pixel
+//  depends on the encoder and the content. A fixed guess either reads
+//  too much, wasting network traffic, or too little, giving blurry images.
+//  Whenever the codestream layout gives the exact number of bytes needed,
+//  it is recorded here, by progression order and discard level, once the
+//  image has decoded. Each image and discard level is recorded once.
+//  Estimates, for files whose layout doesn't give the number, then use what
+//  has been seen for their progression order, plus a safety margin.
+//
+//  Only resolution-major files, RLCP and RPCL, give exact numbers, so only
+//  they learn. In other orders, every resolution is spread over the whole
+//  file, and nothing learned from resolution-major files applies. Those
+//  are always estimated with the fixed guess.
+//
+//  Statistics are for this process, and can be kept in a file, loaded
+//  at start and added to as the process runs. Several decoder processes,
+//  such as those in a pool, can share one file. Each adds what it has
+//  learned since its last save to what is in the file, so none overwrites
+//  another's. Saves are periodic, so a process which is killed loses little.
+//
+use jpeg2000_decoder::ProgressionOrder;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Progression orders, in the order they are tracked.
+const ORDERS: [ProgressionOrder; 5] = [
+    ProgressionOrder::Lrcp,
+    ProgressionOrder::Rlcp,
+    ProgressionOrder::Rpcl,
+    ProgressionOrder::Pcrl,
+    ProgressionOrder::Cprl,
+];
+/// Discard levels tracked. Higher levels are lumped in with the last one.
+const DISCARD_LEVELS: usize = 8;
+/// Don't trust learned ratios with fewer samples than this.
+const MIN_SAMPLES: u64 = 8;
+/// Safety margin, in standard deviations above the mean.
+const SAFETY_MARGIN: f64 = 2.0;
+
+/// Save to the statistics file this often, when there is something new.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Images remembered as recorded. Past this, the list starts over.
+const MAX_RECORDED: usize = 100_000;
+
+/// Statistics for this process.
+pub static COMPRESSION_STATS: Mutex<CompressionStats> = Mutex::new(CompressionStats::new());
+
+/// The statistics file, if any.
+static STATS_FILE: Mutex<Option<StatsFile>> = Mutex::new(None);
+
+/// Images and discard levels already recorded, so repeat requests and upgrades don't count twice.
+static RECORDED: Mutex<BTreeSet<(u64, u32)>> = Mutex::new(BTreeSet::new());
+
+/// A statistics file, and what has been learned since it was last saved.
+struct StatsFile {
+    /// Path to the file
+    path: PathBuf,
+    /// Learned since the last save
+    unsaved: CompressionStats,
+    /// Time of the last save
+    last_save: Instant,
+}
+
+/// Record that this many bytes were needed for this many output pixels,
+/// in the statistics for this process, and for the statistics file.
+/// Nothing is recorded if this image, identified by image_key, was already recorded at this discard level.
+pub fn record_compression(image_key: u64, order: ProgressionOrder, discard_level: u32, bytes: usize, out_pixels: u64) {
+    if !first_record(image_key, discard_level) {
+        return;
+    }
+    COMPRESSION_STATS.lock().unwrap().record(order, discard_level, bytes, out_pixels);
+    if let Some(file) = STATS_FILE.lock().unwrap().as_mut() {
+        file.unsaved.record(order, discard_level, bytes, out_pixels);
+    }
+}
+
+/// True the first time an image and discard level are seen.
+fn first_record(image_key: u64, discard_level: u32) -> bool {
+    let mut recorded = RECORDED.lock().unwrap();
+    if recorded.len() >= MAX_RECORDED {
+        recorded.clear(); // an image recorded again after this only counts twice
+    }
+    recorded.insert((image_key, discard_level))
+}
+
+/// Start with what earlier runs learned, from a statistics file, and save to it from now on.
+pub fn open_stats_file(path: &Path) -> Result<(), std::io::Error> {
+    *COMPRESSION_STATS.lock().unwrap() = CompressionStats::load(path)?;
+    *STATS_FILE.lock().unwrap() = Some(StatsFile {
+        path: path.to_path_buf(),
+        unsaved: CompressionStats::new(),
+        last_save: Instant::now(),
+    });
+    Ok(())
+}
+
+/// Add what has been learned since the last save to the statistics file, if there is one.
+/// Unless forced, does nothing if the last save was recent.
+pub fn save_stats_file(force: bool) -> Result<(), std::io::Error> {
+    let mut file_opt = STATS_FILE.lock().unwrap();
+    let file = match file_opt.as_mut() {
+        Some(file) if force || file.last_save.elapsed() >= SAVE_INTERVAL => file,
+        _ => return Ok(()),
+    };
+    file.last_save = Instant::now();
+    if file.unsaved == CompressionStats::new() {
+        return Ok(()); // nothing new
+    }
+    file.unsaved.merge_into_file(&file.path)?;
+    file.unsaved = CompressionStats::new();
+    Ok(())
+}
+
+/// Bytes needed per output pixel, at one discard level.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LevelStats {
+    /// Number of images seen
+    pub samples: u64,
+    /// Mean bytes per output pixel
+    pub mean: f64,
+    /// Sum of squared differences from the mean, for the variance.
+    m2: f64,
+    /// Largest bytes per output pixel seen
+    pub max: f64,
+}
+
+impl LevelStats {
+    /// Empty.
+    const fn new() -> Self {
+        LevelStats {
+            samples: 0,
+            mean: 0.0,
+            m2: 0.0,
+            max: 0.0,
+        }
+    }
+
+    /// Add one observation. Welford's method, so the variance is stable.
+    fn add(&mut self, bytes_per_pixel: f64) {
+        self.samples += 1;
+        let delta = bytes_per_pixel - self.mean;
+        self.mean += delta / self.samples as f64;
+        self.m2 += delta * (bytes_per_pixel - self.mean);
+        self.max = self.max.max(bytes_per_pixel);
+    }
+
+    /// Combine with statistics gathered separately. Chan's parallel form of Welford's method.
+    fn merge(&mut self, other: &LevelStats) {
+        if other.samples == 0 {
+            return;
+        }
+        let samples = self.samples + other.samples;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.samples as f64 / samples as f64;
+        self.m2 += other.m2 + delta * delta * self.samples as f64 * other.samples as f64 / samples as f64;
+        self.max = self.max.max(other.max);
+        self.samples = samples;
+    }
+
+    /// Standard deviation of bytes per output pixel.
+    pub fn std_dev(&self) -> f64 {
+        if self.samples < 2 {
+            0.0
+        } else {
+            (self.m2 / (self.samples - 1) as f64).sqrt()
+        }
+    }
+
+    /// Bytes per output pixel to read, with safety margin. None if too few samples.
+    pub fn estimate(&self) -> Option<f64> {
+        if self.samples < MIN_SAMPLES {
+            None
+        } else {
+            Some(self.mean + SAFETY_MARGIN * self.std_dev())
+        }
+    }
+}
+
+/// Index of a progression order in ORDERS.
+fn order_index(order: ProgressionOrder) -> usize {
+    ORDERS.iter().position(|o| *o == order).expect("Unknown progression order")
+}
+
+/// Name of a progression order, as in the standard. "RPCL", etc.
+fn order_name(order: ProgressionOrder) -> String {
+    format!("{:?}", order).to_ascii_uppercase()
+}
+
+/// Bytes needed per output pixel, for each progression order and discard level.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompressionStats {
+    /// By progression order, then discard level
+    levels: [[LevelStats; DISCARD_LEVELS]; ORDERS.len()],
+}
+
+impl CompressionStats {
+    /// Empty.
+    pub const fn new() -> Self {
+        CompressionStats {
+            levels: [[LevelStats::new(); DISCARD_LEVELS]; ORDERS.len()],
+        }
+    }
+
+    /// Stats for a progression order and discard level.
+    pub fn level(&self, order: ProgressionOrder, discard_level: u32) -> &LevelStats {
+        &self.levels[order_index(order)][(discard_level as usize).min(DISCARD_LEVELS - 1)]
+    }
+
+    /// Record that this many bytes were needed for this many output pixels.
+    pub fn record(&mut self, order: ProgressionOrder, discard_level: u32, bytes: usize, out_pixels: u64) {
+        if out_pixels > 0 {
+            self.levels[order_index(order)][(discard_level as usize).min(DISCARD_LEVELS - 1)].add(bytes as f64 / out_pixels as f64);
+        }
+    }
+
+    /// Combine with statistics gathered separately.
+    pub fn merge(&mut self, other: &CompressionStats) {
+        for (levels, other_levels) in self.levels.iter_mut().zip(other.levels.iter()) {
+            for (level, other_level) in levels.iter_mut().zip(other_levels.iter()) {
+                level.merge(other_level);
+            }
+        }
+    }
+
+    /// Bytes per output pixel to read at a discard level, for a progression order, if enough has been learned.
+    pub fn bytes_per_pixel(&self, order: ProgressionOrder, discard_level: u32) -> Option<f64> {
+        self.level(order, discard_level).estimate()
+    }
+
+    /// Each progression order and discard level, with its stats.
+    fn all_levels(&self) -> impl Iterator<Item = (ProgressionOrder, usize, &LevelStats)> {
+        ORDERS
+            .iter()
+            .zip(self.levels.iter())
+            .flat_map(|(order, levels)| levels.iter().enumerate().map(move |(level, stats)| (*order, level, stats)))
+    }
+
+    /// Human-readable report.
+    pub fn report(&self) -> String {
+        let mut s = String::from("Order, discard level, samples, mean, std dev, max, estimate (bytes per output pixel)\n");
+        for (order, level, stats) in self.all_levels().filter(|(_, _, stats)| stats.samples > 0) {
+            let _ = writeln!(
+                s,
+                "{} {:>2} {:>8} {:>8.3} {:>8.3} {:>8.3} {:>8}",
+                order_name(order),
+                level,
+                stats.samples,
+                stats.mean,
+                stats.std_dev(),
+                stats.max,
+                stats.estimate().map_or("-".to_string(), |v| format!("{:.3}", v))
+            );
+        }
+        s
+    }
+
+    /// Save to a file. One line per progression order and discard level which has samples.
+    pub fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        let mut s = String::new();
+        for (order, level, stats) in self.all_levels().filter(|(_, _, stats)| stats.samples > 0) {
+            let _ = writeln!(s, "{} {} {} {} {} {}", order_name(order), level, stats.samples, stats.mean, stats.m2, stats.max);
+        }
+        std::fs::write(path, s)
+    }
+
+    /// Add to what is in a file, which other processes may also be adding to.
+    /// The new file is written under another name, then renamed, so a reader
+    /// never sees a partly written file. Two processes saving at the same moment
+    /// can lose one's additions, which only slows learning.
+    pub fn merge_into_file(&self, path: &Path) -> Result<(), std::io::Error> {
+        let mut stats = CompressionStats::load(path)?;
+        stats.merge(self);
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(".{}.tmp", std::process::id()));
+        let tmp_path = PathBuf::from(tmp_path);
+        let status = stats.save(&tmp_path).and_then(|_| std::fs::rename(&tmp_path, path));
+        if status.is_err() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        status
+    }
+
+    /// Load from a file written by save. A missing file is not an error; there is nothing learned yet.
+    pub fn load(path: &Path) -> Result<CompressionStats, std::io::Error> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(CompressionStats::new()),
+            Err(e) => return Err(e),
+        };
+        let bad = |line: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Bad line in statistics file: \"{}\"", line));
+        let mut stats = CompressionStats::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 6 {
+                return Err(bad(line));
+            }
+            let order = ORDERS.iter().position(|order| order_name(*order) == fields[0]).ok_or_else(|| bad(line))?;
+            let level: usize = fields[1].parse().map_err(|_| bad(line))?;
+            let samples: u64 = fields[2].parse().map_err(|_| bad(line))?;
+            let values: Vec<f64> = fields[3..].iter().map(|f| f.parse::<f64>()).collect::<Result<_, _>>().map_err(|_| bad(line))?;
+            if level >= DISCARD_LEVELS || values.iter().any(|v| !v.is_finite() || *v < 0.0) {
+                return Err(bad(line));
+            }
+            stats.levels[order][level] = LevelStats {
+                samples,
+                mean: values[0],
+                m2: values[1],
+                max: values[2],
+            };
+        }
+        Ok(stats)
+    }
+}
+
+#[test]
+fn test_compression_stats() {
+    use ProgressionOrder::{Lrcp, Rpcl};
+    let mut stats = CompressionStats::new();
+    for i in 0..MIN_SAMPLES - 1 {
+        stats.record(Rpcl, 2, 1000 + 100 * i as usize, 1000);
+    }
+    assert_eq!(stats.bytes_per_pixel(Rpcl, 2), None); // not enough samples yet
+    stats.record(Rpcl, 2, 1700, 1000);
+    let level = stats.level(Rpcl, 2);
+    assert_eq!(level.samples, MIN_SAMPLES);
+    assert!((level.mean - 1.35).abs() < 1e-9);
+    assert!((level.max - 1.7).abs() < 1e-9);
+    assert!(stats.bytes_per_pixel(Rpcl, 2).unwrap() > level.mean); // safety margin
+    assert_eq!(stats.bytes_per_pixel(Rpcl, 1), None);
+    assert_eq!(stats.bytes_per_pixel(Lrcp, 2), None); // other orders learn separately
+    stats.record(Rpcl, 20, 100, 100); // lumped in with the last level
+    assert_eq!(stats.level(Rpcl, DISCARD_LEVELS as u32 - 1).samples, 1);
+    //  Save and load
+    let path = std::env::temp_dir().join(format!("jpeg2000-decoder-stats-{}.txt", std::process::id()));
+    stats.save(&path).unwrap();
+    assert_eq!(CompressionStats::load(&path).unwrap(), stats);
+    //  Merging gives the same result as recording everything in one place.
+    let mut other = CompressionStats::new();
+    let mut all = stats.clone();
+    for i in 0..5 {
+        other.record(Rpcl, 2, 2000 + 300 * i, 1000);
+        all.record(Rpcl, 2, 2000 + 300 * i, 1000);
+    }
+    other.record(Lrcp, 2, 500, 1000);
+    all.record(Lrcp, 2, 500, 1000);
+    other.merge_into_file(&path).unwrap();
+    let merged = CompressionStats::load(&path).unwrap();
+    assert_eq!(merged.level(Rpcl, 2).samples, all.level(Rpcl, 2).samples);
+    assert!((merged.level(Rpcl, 2).mean - all.level(Rpcl, 2).mean).abs() < 1e-9);
+    assert!((merged.level(Rpcl, 2).std_dev() - all.level(Rpcl, 2).std_dev()).abs() < 1e-9);
+    assert_eq!(merged.level(Rpcl, 2).max, all.level(Rpcl, 2).max);
+    assert_eq!(merged.level(Rpcl, DISCARD_LEVELS as u32 - 1).samples, 1);
+    assert_eq!(merged.level(Lrcp, 2), all.level(Lrcp, 2));
+    std::fs::write(&path, "RPCL 2 8 NaN 0 0\n").unwrap();
+    assert!(CompressionStats::load(&path).is_err());
+    std::fs::write(&path, "XYZW 2 8 1 0 1\n").unwrap();
+    assert!(CompressionStats::load(&path).is_err()); // no such order
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(CompressionStats::load(&path).unwrap(), CompressionStats::new()); // missing file
+    //  Each image is recorded once per discard level.
+    const IMAGE_KEY: u64 = 0x5eed_0000_0000_0001;
+    assert!(first_record(IMAGE_KEY, 2));
+    assert!(!first_record(IMAGE_KEY, 2));
+    assert!(first_record(IMAGE_KEY, 3));
+}
@@ -0,0 +1,625 @@
+//! # header.rs  -- JPEG 2000 header parsing, without the decoder.
+//!
+//  Animats
+//  March, 2023
+//
+//! Reads the main header of a JPEG 2000 codestream, and the JP2 boxes
+//! around it if present, from the first bytes of a file.
+//! Everything is bounds checked, so untrusted files can be examined
+//! safely, without running OpenJPEG.
+//!
+//! Marker segment and box layouts are from ISO/IEC 15444-1, Annex A and Annex I.
+
+//  Codestream markers
+pub(crate) const SOC: u16 = 0xFF4F; // start of codestream
+pub(crate) const SIZ: u16 = 0xFF51; // image and tile size
+pub(crate) const COD: u16 = 0xFF52; // coding style default
+const COC: u16 = 0xFF53; // coding style component
+pub(crate) const TLM: u16 = 0xFF55; // tile-part lengths
+pub(crate) const QCD: u16 = 0xFF5C; // quantization default
+const POC: u16 = 0xFF5F; // progression order change
+const PPM: u16 = 0xFF60; // packed packet headers, main header
+pub const SOT: u16 = 0xFF90; // start of tile-part, ends the main header
+pub const EOC: u16 = 0xFFD9; // end of codestream
+
+//  JP2 box types
+const BOX_SIGNATURE: u32 = 0x6A50_2020; // "jP  "
+const BOX_HEADER: u32 = 0x6A70_3268; // "jp2h"
+const BOX_IMAGE_HEADER: u32 = 0x6968_6472; // "ihdr"
+const BOX_COLOUR: u32 = 0x636F_6C72; // "colr"
+const BOX_CODESTREAM: u32 = 0x6A70_3263; // "jp2c"
+/// Contents of the signature box.
+const JP2_SIGNATURE: u32 = 0x0D0A_870A;
+
+/// Progression order of packets in the codestream. From the COD marker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressionOrder {
+    /// Layer, resolution, component, position
+    Lrcp,
+    /// Resolution, layer, component, position
+    Rlcp,
+    /// Resolution, position, component, layer
+    Rpcl,
+    /// Position, component, resolution, layer
+    Pcrl,
+    /// Component, position, resolution, layer
+    Cprl,
+}
+
+/// One image component. From the SIZ marker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComponentInfo {
+    /// Bits per sample
+    pub precision: u8,
+    /// Samples are signed
+    pub signed: bool,
+    /// Horizontal subsampling
+    pub dx: u8,
+    /// Vertical subsampling
+    pub dy: u8,
+}
+
+/// From the JP2 header box, if the file is JP2 rather than a bare codestream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Jp2Info {
+    /// Image width, pixels
+    pub width: u32,
+    /// Image height, pixels
+    pub height: u32,
+    /// Number of components
+    pub components: u16,
+    /// Bits per component, or None if they differ by component.
+    pub bits_per_component: Option<u8>,
+    /// Enumerated colour space from the colour box, if any.
+    /// 16 is sRGB, 17 is greyscale, 18 is sYCC.
+    pub colorspace: Option<u32>,
+}
+
+/// Main header of a JPEG 2000 image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Image width, pixels
+    pub width: u32,
+    /// Image height, pixels
+    pub height: u32,
+    /// Offset of the image area on the reference grid
+    pub x_offset: u32,
+    /// Offset of the image area on the reference grid
+    pub y_offset: u32,
+    /// Components, usually 1 to 4.
+    pub components: Vec<ComponentInfo>,
+    /// Tile width, on the reference grid
+    pub tile_width: u32,
+    /// Tile height, on the reference grid
+    pub tile_height: u32,
+    /// Offset of the tile grid
+    pub tile_x_offset: u32,
+    /// Offset of the tile grid
+    pub tile_y_offset: u32,
+    /// Number of resolution levels, one more than the number of wavelet decomposition levels.
+    /// Discard levels 0 to resolution_levels - 1 are available.
+    pub resolution_levels: u8,
+    /// Number of quality layers
+    pub layers: u16,
+    /// Precinct sizes, as (log2 width, log2 height), for each resolution level, lowest first.
+    pub precinct_sizes: Vec<(u8, u8)>,
+    /// Order of packets in the codestream
+    pub progression_order: ProgressionOrder,
+    /// Multiple component transform, RGB to YCC, is used.
+    pub component_transform: bool,
+    /// Reversible (lossless) wavelet transform
+    pub reversible: bool,
+    /// Quantization style. 0 is none, 1 is scalar derived, 2 is scalar expounded.
+    pub quantization_style: u8,
+    /// Number of guard bits
+    pub guard_bits: u8,
+    /// Lengths of the tile-parts, in codestream order, from TLM markers. Empty if no TLM.
+    pub tile_part_lengths: Vec<u32>,
+    /// Main header has COC, POC, or PPM markers, so packet layout is not just what COD says.
+    pub layout_overrides: bool,
+    /// JP2 header, if a JP2 file.
+    pub jp2: Option<Jp2Info>,
+    /// Offset in the file of the start of the codestream, the SOC marker.
+    pub codestream_offset: usize,
+    /// Offset in the file of the end of the main header, where the first tile-part starts.
+    pub main_header_end: usize,
+}
+
+impl ImageHeader {
+    /// Number of tiles across and down.
+    pub fn tiles(&self) -> (u32, u32) {
+        let count = |end: u32, offset: u32, size: u32| {
+            u64::from(end - offset).div_ceil(u64::from(size)) as u32
+        };
+        (
+            count(self.x_offset + self.width, self.tile_x_offset, self.tile_width),
+            count(self.y_offset + self.height, self.tile_y_offset, self.tile_height),
+        )
+    }
+
+    /// Bytes per pixel, rounded up from bits.
+    pub fn bytes_per_pixel(&self) -> u8 {
+        let bits: u32 = self.components.iter().map(|c| u32::from(c.precision)).sum();
+        bits.div_ceil(8).min(u8::MAX.into()) as u8
+    }
+}
+
+/// Problems with a header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// Not enough data. The header needs at least this many bytes.
+    Truncated(usize),
+    /// Not a valid JPEG 2000 header.
+    Invalid(String),
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderError::Truncated(n) => write!(f, "JPEG 2000 header truncated, need {} bytes", n),
+            HeaderError::Invalid(s) => write!(f, "Invalid JPEG 2000 header: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+/// Parse the header of a JPEG 2000 file, either a bare codestream or JP2.
+///
+/// Only the first bytes of the file are needed, through the end of the main header.
+/// If there are not enough, returns `HeaderError::Truncated` with the number needed,
+/// which may still be a lower bound.
+pub fn parse_header(data: &[u8]) -> Result<ImageHeader, HeaderError> {
+    if data.len() < 2 {
+        return Err(HeaderError::Truncated(2));
+    }
+    if get_u16(data, 0)? == SOC {
+        parse_codestream(data, 0, None)
+    } else {
+        let (jp2, codestream_offset) = parse_jp2(data)?;
+        parse_codestream(data, codestream_offset, Some(jp2))
+    }
+}
+
+/// pos + n, or an error if a bad length sends it past the end of memory.
+pub(crate) fn advance(pos: usize, n: usize) -> Result<usize, HeaderError> {
+    pos.checked_add(n).ok_or(HeaderError::Invalid(format!("Offset {} + {} out of range", pos, n)))
+}
+
+/// Big-endian integer at pos.
+pub(crate) fn get_u16(data: &[u8], pos: usize) -> Result<u16, HeaderError> {
+    let end = advance(pos, 2)?;
+    let bytes = data.get(pos..end).ok_or(HeaderError::Truncated(end))?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Big-endian integer at pos.
+pub(crate) fn get_u32(data: &[u8], pos: usize) -> Result<u32, HeaderError> {
+    let end = advance(pos, 4)?;
+    let bytes = data.get(pos..end).ok_or(HeaderError::Truncated(end))?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Big-endian integer at pos.
+fn get_u64(data: &[u8], pos: usize) -> Result<u64, HeaderError> {
+    Ok((u64::from(get_u32(data, pos)?) << 32) | u64::from(get_u32(data, advance(pos, 4)?)?))
+}
+
+/// Byte at pos.
+pub(crate) fn get_u8(data: &[u8], pos: usize) -> Result<u8, HeaderError> {
+    data.get(pos).copied().ok_or(HeaderError::Truncated(advance(pos, 1)?))
+}
+
+/// Invalid header error.
+fn invalid<T>(msg: impl Into<String>) -> Result<T, HeaderError> {
+    Err(HeaderError::Invalid(msg.into()))
+}
+
+/// A box in a JP2 file.
+struct Jp2Box {
+    /// Box type
+    box_type: u32,
+    /// Offset of the contents
+    start: usize,
+    /// Offset of the end of the box. None if the box runs to the end of the file.
+    end: Option<usize>,
+}
+
+/// Read the box header at pos.
+fn get_box(data: &[u8], pos: usize) -> Result<Jp2Box, HeaderError> {
+    let length = get_u32(data, pos)?;
+    let box_type = get_u32(data, advance(pos, 4)?)?;
+    let (start, length) = match length {
+        0 => (advance(pos, 8)?, None),
+        1 => (advance(pos, 16)?, Some(get_u64(data, advance(pos, 8)?)?)), // extended length
+        n => (advance(pos, 8)?, Some(u64::from(n))),
+    };
+    let end = match length {
+        Some(length) => {
+            let end = usize::try_from(length)
+                .ok()
+                .and_then(|length| pos.checked_add(length))
+                .ok_or(HeaderError::Invalid(format!("Box length {} too large", length)))?;
+            if end < start {
+                return invalid(format!("Box length {} too small", length));
+            }
+            Some(end)
+        }
+        None => None,
+    };
+    Ok(Jp2Box {
+        box_type,
+        start,
+        end,
+    })
+}
+
+/// Parse the JP2 boxes before the codestream.
+/// Returns the JP2 header information, and the offset of the codestream.
+fn parse_jp2(data: &[u8]) -> Result<(Jp2Info, usize), HeaderError> {
+    let signature = get_box(data, 0)?;
+    if signature.box_type != BOX_SIGNATURE || get_u32(data, signature.start)? != JP2_SIGNATURE {
+        return invalid("Not a JPEG 2000 codestream or JP2 file");
+    }
+    let mut pos = signature.end.ok_or(HeaderError::Invalid("Bad signature box".to_string()))?;
+    let mut jp2_opt = None;
+    loop {
+        let b = get_box(data, pos)?;
+        match b.box_type {
+            BOX_CODESTREAM => {
+                let jp2 = jp2_opt.ok_or(HeaderError::Invalid("No JP2 header box".to_string()))?;
+                return Ok((jp2, b.start));
+            }
+            BOX_HEADER => {
+                let end = b.end.ok_or(HeaderError::Invalid("JP2 header box runs to end of file".to_string()))?;
+                if end > data.len() {
+                    return Err(HeaderError::Truncated(end));
+                }
+                jp2_opt = Some(parse_jp2_header(&data[..end], b.start)?);
+                pos = end;
+            }
+            _ => {
+                pos = b.end.ok_or(HeaderError::Invalid("No codestream box".to_string()))?;
+            }
+        }
+    }
+}
+
+/// Parse the boxes inside the JP2 header box, which are all in data.
+/// Reads past the end are errors, not truncations.
+fn parse_jp2_header(data: &[u8], mut pos: usize) -> Result<Jp2Info, HeaderError> {
+    let mut info_opt = None;
+    let mut colorspace = None;
+    while pos < data.len() {
+        let b = get_box(data, pos).map_err(|e| match e {
+            HeaderError::Truncated(_) => HeaderError::Invalid("Box header past end of JP2 header box".to_string()),
+            e => e,
+        })?;
+        let end = b.end.unwrap_or(data.len());
+        if end > data.len() {
+            return invalid("Box extends past end of JP2 header box");
+        }
+        let contents = &data[..end];
+        let bad_box = |_| HeaderError::Invalid("Box in JP2 header box too short".to_string());
+        match b.box_type {
+            BOX_IMAGE_HEADER => {
+                let bpc = get_u8(contents, b.start + 10).map_err(bad_box)?;
+                info_opt = Some(Jp2Info {
+                    height: get_u32(contents, b.start).map_err(bad_box)?,
+                    width: get_u32(contents, b.start + 4).map_err(bad_box)?,
+                    components: get_u16(contents, b.start + 8).map_err(bad_box)?,
+                    bits_per_component: if bpc == 0xFF { None } else { Some((bpc & 0x7F) + 1) },
+                    colorspace: None,
+                });
+            }
+            //  Method 1 is an enumerated colour space. Others are ICC profiles.
+            BOX_COLOUR if get_u8(contents, b.start).map_err(bad_box)? == 1 => {
+                colorspace = Some(get_u32(contents, b.start + 3).map_err(bad_box)?);
+            }
+            _ => {}
+        }
+        pos = end;
+    }
+    let mut info = info_opt.ok_or(HeaderError::Invalid("No image header box".to_string()))?;
+    info.colorspace = colorspace;
+    Ok(info)
+}
+
+/// Parse the main header of the codestream starting at offset.
+fn parse_codestream(data: &[u8], offset: usize, jp2: Option<Jp2Info>) -> Result<ImageHeader, HeaderError> {
+    if get_u16(data, offset)? != SOC {
+        return invalid("No start of codestream marker");
+    }
+    let mut header_opt: Option<ImageHeader> = None;
+    let mut have_cod = false;
+    let mut have_qcd = false;
+    let mut tlm_segments: Vec<(u8, Vec<u32>)> = Vec::new();
+    let mut pos = advance(offset, 2)?;
+    loop {
+        let marker = get_u16(data, pos)?;
+        if marker == SOT {
+            break; // end of main header
+        }
+        if marker == EOC || marker < 0xFF00 {
+            return invalid(format!("Unexpected marker {:04x} in main header", marker));
+        }
+        let length = usize::from(get_u16(data, advance(pos, 2)?)?);
+        if length < 2 {
+            return invalid(format!("Marker {:04x} segment length {} too small", marker, length));
+        }
+        let end = advance(pos, 2 + length)?;
+        if end > data.len() {
+            return Err(HeaderError::Truncated(end));
+        }
+        let segment = &data[pos + 4..end];
+        match (marker, header_opt.as_mut()) {
+            (SIZ, None) => header_opt = Some(parse_siz(segment, jp2.clone(), offset)?),
+            (_, None) => return invalid("SIZ is not the first marker"),
+            (SIZ, Some(_)) => return invalid("Extra SIZ marker"),
+            (COD, Some(header)) => {
+                parse_cod(segment, header)?;
+                have_cod = true;
+            }
+            (QCD, Some(header)) => {
+                let sqcd = *segment.first().ok_or(HeaderError::Invalid("QCD too short".to_string()))?;
+                header.quantization_style = sqcd & 0x1F;
+                header.guard_bits = sqcd >> 5;
+                have_qcd = true;
+            }
+            (TLM, Some(_)) => tlm_segments.push(parse_tlm(segment)?),
+            (COC | POC | PPM, Some(header)) => header.layout_overrides = true,
+            _ => {} // markers we do not need
+        }
+        pos = end;
+    }
+    let mut header = header_opt.ok_or(HeaderError::Invalid("No SIZ marker".to_string()))?;
+    if !have_cod || !have_qcd {
+        return invalid("No COD or QCD marker");
+    }
+    //  TLM segments can be in any order. Their index says where they go.
+    tlm_segments.sort_by_key(|(index, _)| *index);
+    header.tile_part_lengths = tlm_segments.into_iter().flat_map(|(_, lengths)| lengths).collect();
+    header.main_header_end = pos;
+    Ok(header)
+}
+
+/// Parse the image and tile size marker segment.
+fn parse_siz(segment: &[u8], jp2: Option<Jp2Info>, offset: usize) -> Result<ImageHeader, HeaderError> {
+    let bad = |_| HeaderError::Invalid("SIZ too short".to_string());
+    let x_size = get_u32(segment, 2).map_err(bad)?;
+    let y_size = get_u32(segment, 6).map_err(bad)?;
+    let x_offset = get_u32(segment, 10).map_err(bad)?;
+    let y_offset = get_u32(segment, 14).map_err(bad)?;
+    let tile_width = get_u32(segment, 18).map_err(bad)?;
+    let tile_height = get_u32(segment, 22).map_err(bad)?;
+    let tile_x_offset = get_u32(segment, 26).map_err(bad)?;
+    let tile_y_offset = get_u32(segment, 30).map_err(bad)?;
+    let component_count = usize::from(get_u16(segment, 34).map_err(bad)?);
+    if component_count == 0 || segment.len() != 36 + 3 * component_count {
+        return invalid(format!("SIZ length does not match {} components", component_count));
+    }
+    if x_size <= x_offset || y_size <= y_offset {
+        return invalid("Image size is zero");
+    }
+    if tile_width == 0 || tile_height == 0 {
+        return invalid("Tile size is zero");
+    }
+    if tile_x_offset > x_offset
+        || tile_y_offset > y_offset
+        || u64::from(tile_x_offset) + u64::from(tile_width) <= u64::from(x_offset)
+        || u64::from(tile_y_offset) + u64::from(tile_height) <= u64::from(y_offset)
+    {
+        return invalid("First tile does not overlap the image");
+    }
+    let components = segment[36..]
+        .chunks_exact(3)
+        .map(|c| ComponentInfo {
+            precision: (c[0] & 0x7F) + 1,
+            signed: c[0] & 0x80 != 0,
+            dx: c[1],
+            dy: c[2],
+        })
+        .collect::<Vec<ComponentInfo>>();
+    if components.iter().any(|c| c.precision > 38 || c.dx == 0 || c.dy == 0) {
+        return invalid("Bad component precision or subsampling");
+    }
+    Ok(ImageHeader {
+        width: x_size - x_offset,
+        height: y_size - y_offset,
+        x_offset,
+        y_offset,
+        components,
+        tile_width,
+        tile_height,
+        tile_x_offset,
+        tile_y_offset,
+        resolution_levels: 0,
+        layers: 0,
+        precinct_sizes: Vec::new(),
+        progression_order: ProgressionOrder::Lrcp,
+        component_transform: false,
+        reversible: false,
+        quantization_style: 0,
+        guard_bits: 0,
+        tile_part_lengths: Vec::new(),
+        layout_overrides: false,
+        jp2,
+        codestream_offset: offset,
+        main_header_end: 0,
+    })
+}
+
+/// Parse the coding style default marker segment.
+fn parse_cod(segment: &[u8], header: &mut ImageHeader) -> Result<(), HeaderError> {
+    let bad = |_| HeaderError::Invalid("COD too short".to_string());
+    let scod = get_u8(segment, 0).map_err(bad)?;
+    header.progression_order = match get_u8(segment, 1).map_err(bad)? {
+        0 => ProgressionOrder::Lrcp,
+        1 => ProgressionOrder::Rlcp,
+        2 => ProgressionOrder::Rpcl,
+        3 => ProgressionOrder::Pcrl,
+        4 => ProgressionOrder::Cprl,
+        n => return invalid(format!("Unknown progression order {}", n)),
+    };
+    header.layers = get_u16(segment, 2).map_err(bad)?;
+    header.component_transform = get_u8(segment, 4).map_err(bad)? != 0;
+    let decomposition_levels = get_u8(segment, 5).map_err(bad)?;
+    if header.layers == 0 || decomposition_levels > 32 {
+        return invalid("Bad layer count or decomposition levels");
+    }
+    header.resolution_levels = decomposition_levels + 1;
+    header.reversible = get_u8(segment, 9).map_err(bad)? == 1;
+    //  Precinct sizes, one byte per resolution level if present. Default is 2^15, which is one precinct.
+    header.precinct_sizes = if scod & 1 != 0 {
+        let sizes = segment.get(10..10 + usize::from(header.resolution_levels)).ok_or(HeaderError::Invalid("COD too short for precinct sizes".to_string()))?;
+        sizes.iter().map(|b| (b & 0x0F, b >> 4)).collect()
+    } else {
+        vec![(15, 15); usize::from(header.resolution_levels)]
+    };
+    Ok(())
+}
+
+/// Parse a tile-part lengths marker segment. Returns its index and the lengths.
+fn parse_tlm(segment: &[u8]) -> Result<(u8, Vec<u32>), HeaderError> {
+    let bad = |_| HeaderError::Invalid("TLM too short".to_string());
+    let index = get_u8(segment, 0).map_err(bad)?;
+    let stlm = get_u8(segment, 1).map_err(bad)?;
+    //  Size of the tile index, 0, 1 or 2 bytes, and of the length, 2 or 4 bytes.
+    let tile_index_size = match (stlm >> 4) & 0x03 {
+        3 => return invalid("Bad TLM tile index size"),
+        n => usize::from(n),
+    };
+    let length_size = if stlm & 0x40 != 0 { 4 } else { 2 };
+    let entries = &segment[2..];
+    if !entries.len().is_multiple_of(tile_index_size + length_size) {
+        return invalid("TLM length is not a whole number of entries");
+    }
+    let lengths = entries
+        .chunks_exact(tile_index_size + length_size)
+        .map(|entry| {
+            let length = &entry[tile_index_size..];
+            if length_size == 4 {
+                u32::from_be_bytes([length[0], length[1], length[2], length[3]])
+            } else {
+                u32::from(u16::from_be_bytes([length[0], length[1]]))
+            }
+        })
+        .collect();
+    Ok((index, lengths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdata::marker;
+
+    /// Main header of a 256 x 128 RGB codestream, one tile, 5 layers, RPCL, 6 resolution levels.
+    fn test_codestream() -> Vec<u8> {
+        let mut out = SOC.to_be_bytes().to_vec();
+        let mut siz = vec![0, 0]; // Rsiz
+        for v in [256_u32, 128, 0, 0, 256, 128, 0, 0] {
+            siz.extend_from_slice(&v.to_be_bytes());
+        }
+        siz.extend_from_slice(&3_u16.to_be_bytes());
+        for _ in 0..3 {
+            siz.extend_from_slice(&[7, 1, 1]); // 8 bits unsigned, no subsampling
+        }
+        marker(&mut out, SIZ, &siz);
+        marker(&mut out, COD, &[0, 2, 0, 5, 1, 5, 4, 4, 0, 0]); // RPCL, 5 layers, MCT, 5 levels, 9-7
+        marker(&mut out, 0xFF64, b"\x00\x01Comment"); // COM, skipped
+        marker(&mut out, QCD, &[0x42, 0x48, 0x50]);
+        out.extend_from_slice(&SOT.to_be_bytes());
+        out
+    }
+
+    /// Append a box.
+    fn jp2_box(out: &mut Vec<u8>, box_type: u32, contents: &[u8]) {
+        out.extend_from_slice(&(contents.len() as u32 + 8).to_be_bytes());
+        out.extend_from_slice(&box_type.to_be_bytes());
+        out.extend_from_slice(contents);
+    }
+
+    /// The same image, as JP2.
+    fn test_jp2() -> Vec<u8> {
+        let mut out = Vec::new();
+        jp2_box(&mut out, BOX_SIGNATURE, &JP2_SIGNATURE.to_be_bytes());
+        jp2_box(&mut out, 0x6674_7970, b"jp2 \0\0\0\0jp2 "); // ftyp
+        let mut header = Vec::new();
+        jp2_box(&mut header, BOX_IMAGE_HEADER, &[0, 0, 0, 128, 0, 0, 1, 0, 0, 3, 7, 7, 0, 0]);
+        jp2_box(&mut header, BOX_COLOUR, &[1, 0, 0, 0, 0, 0, 16]);
+        jp2_box(&mut out, BOX_HEADER, &header);
+        jp2_box(&mut out, BOX_CODESTREAM, &test_codestream());
+        out
+    }
+
+    #[test]
+    fn test_parse_codestream() {
+        let data = test_codestream();
+        let header = parse_header(&data).unwrap();
+        assert_eq!((header.width, header.height), (256, 128));
+        assert_eq!(header.components.len(), 3);
+        assert_eq!(header.components[0].precision, 8);
+        assert_eq!(header.bytes_per_pixel(), 3);
+        assert_eq!(header.tiles(), (1, 1));
+        assert_eq!(header.resolution_levels, 6);
+        assert_eq!(header.layers, 5);
+        assert_eq!(header.precinct_sizes, vec![(15, 15); 6]);
+        assert!(header.tile_part_lengths.is_empty());
+        assert!(!header.layout_overrides);
+        assert_eq!(header.progression_order, ProgressionOrder::Rpcl);
+        assert!(header.component_transform);
+        assert!(!header.reversible);
+        assert_eq!((header.quantization_style, header.guard_bits), (2, 2));
+        assert_eq!(header.codestream_offset, 0);
+        assert_eq!(header.main_header_end, data.len() - 2);
+        assert!(header.jp2.is_none());
+    }
+
+    #[test]
+    fn test_parse_jp2() {
+        let data = test_jp2();
+        let header = parse_header(&data).unwrap();
+        assert_eq!((header.width, header.height), (256, 128));
+        let jp2 = header.jp2.unwrap();
+        assert_eq!((jp2.width, jp2.height, jp2.components), (256, 128, 3));
+        assert_eq!(jp2.bits_per_component, Some(8));
+        assert_eq!(jp2.colorspace, Some(16));
+        assert_eq!(header.main_header_end, data.len() - 2);
+        assert_eq!(&data[header.codestream_offset..header.codestream_offset + 2], &[0xFF, 0x4F]);
+    }
+
+    #[test]
+    fn test_parse_header_truncated() {
+        //  Every short prefix asks for more bytes than it has.
+        for data in [test_codestream(), test_jp2()] {
+            for n in 0..data.len() {
+                match parse_header(&data[..n]) {
+                    Err(HeaderError::Truncated(needed)) => assert!(needed > n && needed <= data.len()),
+                    r => panic!("Length {}: unexpected {:?}", n, r),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_header_corrupt() {
+        assert!(matches!(parse_header(b"GIF89a, not JPEG 2000"), Err(HeaderError::Invalid(_))));
+        //  Damage every byte. Must not panic.
+        for data in [test_codestream(), test_jp2()] {
+            for i in 0..data.len() {
+                for v in [0x00, 0x7F, 0xFF] {
+                    let mut bad = data.clone();
+                    bad[i] = v;
+                    let _ = parse_header(&bad);
+                }
+            }
+        }
+        //  A box length which would run past the end of memory.
+        let mut data = Vec::new();
+        jp2_box(&mut data, BOX_SIGNATURE, &JP2_SIGNATURE.to_be_bytes());
+        data.extend_from_slice(&1_u32.to_be_bytes());
+        data.extend_from_slice(&0x6674_7970_u32.to_be_bytes()); // ftyp
+        data.extend_from_slice(&(usize::MAX as u64 - 13).to_be_bytes());
+        assert!(matches!(parse_header(&data), Err(HeaderError::Invalid(_))));
+    }
+}
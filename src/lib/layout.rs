@@ -0,0 +1,262 @@
+//! # layout.rs  -- where each resolution level ends in a JPEG 2000 codestream.
+//!
+//  Animats
+//  March, 2023
+//
+//! A prefix of a JPEG 2000 file decodes to a lower resolution.
+//! If packets are in resolution-major order, RLCP or RPCL, everything
+//! needed for the lowest n resolution levels comes before anything else.
+//! This finds exactly where that is, from TLM markers in the main header,
+//! or by walking the tile-part headers and their PLT markers.
+//!
+//! Only single-tile images are handled. For anything else, the
+//! caller has to estimate.
+
+use crate::header::{get_u16, get_u32, get_u8, HeaderError, ImageHeader, ProgressionOrder, SOT};
+
+//  Tile-part header markers
+const COD: u16 = 0xFF52; // coding style default
+const COC: u16 = 0xFF53; // coding style component
+pub(crate) const PLT: u16 = 0xFF58; // packet lengths, tile-part header
+const POC: u16 = 0xFF5F; // progression order change
+const PPT: u16 = 0xFF61; // packed packet headers, tile-part header
+pub const SOD: u16 = 0xFF93; // start of data
+
+/// Number of bytes from the start of the file needed to decode at a discard level.
+///
+/// `data` is as much of the file as is available. Tile-part headers
+/// are read from it if the main header does not have the answer.
+///
+/// Returns `Ok(None)` if the codestream layout does not allow an exact answer.
+/// Returns `HeaderError::Truncated` if more of the file is needed to tell.
+/// Everything before that point is needed for the decode anyway.
+pub fn bytes_for_discard_level(header: &ImageHeader, data: &[u8], discard_level: u32) -> Result<Option<usize>, HeaderError> {
+    if !matches!(header.progression_order, ProgressionOrder::Rlcp | ProgressionOrder::Rpcl)
+        || header.tiles() != (1, 1)
+        || header.layout_overrides
+        || header.resolution_levels == 0
+    {
+        return Ok(None);
+    }
+    let levels = u32::from(header.resolution_levels);
+    let resolutions = levels - discard_level.min(levels - 1); // resolution levels to decode
+    //  One tile-part per resolution level, lengths known from TLM. Nothing else to read.
+    if header.tile_part_lengths.len() == levels as usize {
+        let length: u64 = header.tile_part_lengths[..resolutions as usize].iter().map(|n| u64::from(*n)).sum();
+        return Ok(usize::try_from(length).ok().and_then(|n| header.main_header_end.checked_add(n)));
+    }
+    walk_tile_parts(header, data, resolutions)
+}
+
+/// Walk the tile-parts, counting packets, until the packets for the
+/// lowest `resolutions` resolution levels have all gone by.
+fn walk_tile_parts(header: &ImageHeader, data: &[u8], resolutions: u32) -> Result<Option<usize>, HeaderError> {
+    let target = packets_for_resolutions(header, resolutions);
+    let mut counted: u64 = 0;
+    let mut pos = header.main_header_end;
+    loop {
+        if get_u16(data, pos)? != SOT {
+            return Ok(None); // end of codestream, or something unexpected
+        }
+        //  SOT is Lsot, Isot, Psot, TPsot, TNsot.
+        let tile_part_length = get_u32(data, pos + 6)? as usize;
+        let tile_part_index = get_u8(data, pos + 10)?;
+        let tile_part_count = get_u8(data, pos + 11)?;
+        //  Psot of 0 means the last tile-part, running to the end of the codestream.
+        let tile_part_end = if tile_part_length == 0 { None } else { Some(pos.saturating_add(tile_part_length)) };
+        //  Tile-part header, up to SOD.
+        let mut packet_lengths = Vec::new();
+        let mut marker_pos = pos + 12;
+        loop {
+            let marker = get_u16(data, marker_pos)?;
+            if marker == SOD {
+                break;
+            }
+            let length = usize::from(get_u16(data, marker_pos + 2)?);
+            if marker < 0xFF00 || length < 2 {
+                return Ok(None); // not a marker segment
+            }
+            let end = marker_pos + 2 + length;
+            match marker {
+                PLT => {
+                    //  Skip Zplt. PLT segments within a tile-part are in order.
+                    let segment = data.get(marker_pos + 5..end).ok_or(HeaderError::Truncated(end))?;
+                    if !decode_packet_lengths(segment, &mut packet_lengths) {
+                        return Ok(None);
+                    }
+                }
+                COD | COC | POC | PPT => return Ok(None), // layout differs from the main header
+                _ => {}
+            }
+            marker_pos = end;
+        }
+        if packet_lengths.is_empty() {
+            //  No packet lengths. Exact only if there is one tile-part per resolution level.
+            if tile_part_count != header.resolution_levels {
+                return Ok(None);
+            }
+            if u32::from(tile_part_index) + 1 == resolutions {
+                return Ok(tile_part_end);
+            }
+        } else {
+            let mut offset = marker_pos + 2; // start of packet data
+            for length in packet_lengths {
+                if counted == target {
+                    return Ok(Some(offset));
+                }
+                offset = offset.saturating_add(length);
+                counted += 1;
+            }
+            if counted == target {
+                return Ok(Some(offset));
+            }
+            if tile_part_end.is_some_and(|end| end != offset) {
+                return Ok(None); // packet lengths do not add up to the tile-part length
+            }
+        }
+        pos = match tile_part_end {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+    }
+}
+
+/// Decode the packet lengths in a PLT segment. False if the segment is malformed.
+//  Each length is 7 bits per byte, high bit set on all but the last byte.
+fn decode_packet_lengths(segment: &[u8], lengths: &mut Vec<usize>) -> bool {
+    let mut value: u64 = 0;
+    for b in segment {
+        value = (value << 7) | u64::from(b & 0x7F);
+        if value > u64::from(u32::MAX) {
+            return false;
+        }
+        if b & 0x80 == 0 {
+            lengths.push(value as usize);
+            value = 0;
+        }
+    }
+    segment.last().is_none_or(|b| b & 0x80 == 0) // no unfinished length
+}
+
+/// Number of packets in the lowest `resolutions` resolution levels of a single-tile image.
+//  ISO/IEC 15444-1, B.5 and B.6. One packet per layer, component, and precinct.
+fn packets_for_resolutions(header: &ImageHeader, resolutions: u32) -> u64 {
+    let levels = u32::from(header.resolution_levels);
+    let (x0, y0) = (u64::from(header.x_offset), u64::from(header.y_offset));
+    let (x1, y1) = (x0 + u64::from(header.width), y0 + u64::from(header.height));
+    let mut precincts = 0;
+    for component in header.components.iter() {
+        let (dx, dy) = (u64::from(component.dx), u64::from(component.dy));
+        let (cx0, cx1, cy0, cy1) = (x0.div_ceil(dx), x1.div_ceil(dx), y0.div_ceil(dy), y1.div_ceil(dy));
+        for r in 0..resolutions.min(levels) {
+            let scale = 1_u64 << (levels - 1 - r);
+            let (rx0, rx1, ry0, ry1) = (cx0.div_ceil(scale), cx1.div_ceil(scale), cy0.div_ceil(scale), cy1.div_ceil(scale));
+            let (ppx, ppy) = header.precinct_sizes.get(r as usize).copied().unwrap_or((15, 15));
+            //  Precincts are aligned to multiples of their size, so partial ones at the edges count.
+            let count = |start: u64, end: u64, size_log2: u8| {
+                if end > start {
+                    end.div_ceil(1 << size_log2) - (start >> size_log2)
+                } else {
+                    0
+                }
+            };
+            precincts += count(rx0, rx1, ppx) * count(ry0, ry1, ppy);
+        }
+    }
+    precincts * u64::from(header.layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_header;
+    use crate::testdata::{main_header, tile_part};
+
+    /// Packet lengths for resolution level r.
+    fn packets(r: usize) -> Vec<usize> {
+        (0..6).map(|k| 10 + 50 * r * r + k).collect()
+    }
+
+    #[test]
+    fn test_packets_for_resolutions() {
+        let header = parse_header(&[main_header(2, &[]), SOT.to_be_bytes().to_vec()].concat()).unwrap();
+        assert_eq!(packets_for_resolutions(&header, 1), 6);
+        assert_eq!(packets_for_resolutions(&header, 4), 24);
+        //  32 x 32 precincts at full resolution make 4 precincts per component there.
+        let mut header = header;
+        header.precinct_sizes = vec![(15, 15), (15, 15), (15, 15), (5, 5)];
+        assert_eq!(packets_for_resolutions(&header, 3), 18);
+        assert_eq!(packets_for_resolutions(&header, 4), 18 + 3 * 4 * 2);
+    }
+
+    #[test]
+    fn test_tile_part_per_resolution() {
+        //  With TLM, the main header is enough.
+        let lengths: Vec<u32> = (0..4).map(|r| 12 + 2 + packets(r).iter().sum::<usize>() as u32).collect();
+        let data = main_header(1, &lengths);
+        let header = parse_header(&[data.clone(), SOT.to_be_bytes().to_vec()].concat()).unwrap();
+        assert_eq!(header.tile_part_lengths, lengths);
+        let mut file = data.clone();
+        let mut ends = Vec::new();
+        for r in 0..4 {
+            tile_part(&mut file, r as u8, 4, &packets(r), false);
+            ends.push(file.len());
+        }
+        for discard_level in 0..5 {
+            let end = ends[3 - discard_level.min(3) as usize];
+            assert_eq!(bytes_for_discard_level(&header, &data, discard_level), Ok(Some(end)));
+        }
+        //  Without TLM, by walking the tile-part headers.
+        let data = main_header(1, &[]);
+        let mut file = data.clone();
+        let mut ends = Vec::new();
+        for r in 0..4 {
+            tile_part(&mut file, r as u8, 4, &packets(r), false);
+            ends.push(file.len());
+        }
+        let header = parse_header(&file).unwrap();
+        assert_eq!(bytes_for_discard_level(&header, &file, 2), Ok(Some(ends[1])));
+        assert!(matches!(bytes_for_discard_level(&header, &data, 2), Err(HeaderError::Truncated(_))));
+    }
+
+    #[test]
+    fn test_walk_packet_lengths() {
+        //  One tile-part, with PLT.
+        let mut file = main_header(2, &[]);
+        let all: Vec<usize> = (0..4).flat_map(packets).collect();
+        tile_part(&mut file, 0, 1, &all, true);
+        let header = parse_header(&file).unwrap();
+        let data_start = file.len() - all.iter().sum::<usize>();
+        for resolutions in 1..=4 {
+            let end = data_start + all[..6 * resolutions].iter().sum::<usize>();
+            assert_eq!(bytes_for_discard_level(&header, &file, 4 - resolutions as u32), Ok(Some(end)));
+        }
+        //  Only the tile-part header is needed.
+        assert_eq!(bytes_for_discard_level(&header, &file[..data_start], 3), Ok(Some(data_start + all[..6].iter().sum::<usize>())));
+        for n in header.main_header_end..data_start {
+            assert!(matches!(bytes_for_discard_level(&header, &file[..n], 3), Err(HeaderError::Truncated(_))));
+        }
+    }
+
+    #[test]
+    fn test_layout_unknown() {
+        //  Layer-major order. Resolutions are spread through the file.
+        let mut file = main_header(0, &[]);
+        tile_part(&mut file, 0, 1, &(0..4).flat_map(packets).collect::<Vec<usize>>(), true);
+        let header = parse_header(&file).unwrap();
+        assert_eq!(bytes_for_discard_level(&header, &file, 1), Ok(None));
+        //  No PLT, and not one tile-part per resolution level.
+        let mut file = main_header(2, &[]);
+        tile_part(&mut file, 0, 1, &(0..4).flat_map(packets).collect::<Vec<usize>>(), false);
+        let header = parse_header(&file).unwrap();
+        assert_eq!(bytes_for_discard_level(&header, &file, 1), Ok(None));
+        //  Garbage after the main header. Must not panic.
+        for v in [0x00, 0x7F, 0xFF] {
+            for i in header.main_header_end..file.len().min(header.main_header_end + 40) {
+                let mut bad = file.clone();
+                bad[i] = v;
+                let _ = bytes_for_discard_level(&header, &bad, 1);
+            }
+        }
+    }
+}
@@ -1,14 +1,36 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
+//! # jpeg2000-decoder  -- library side of the JPEG 2000 decoder.
+//!
+//! Decoding is done by the jpeg2000_decoder executable, in a subprocess, for safety.
+//! Callers of this library never run the JPEG 2000 decoder in their own process.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use jpeg2000_decoder::{default_decoder_path, DecoderProcess};
+//! let mut decoder = DecoderProcess::new(&default_decoder_path()).unwrap();
+//! let image = decoder.decode("http://www.example.com/file.j2k", Some(128), None).unwrap();
+//! println!("({}, {}, {})", image.width, image.height, image.depth);
+//! ```
+//
+//  Animats
+//  March, 2023
+//
+mod header;
+mod layout;
+mod limits;
+mod options;
+mod pool;
+mod process;
+pub mod protocol;
+mod queue;
+#[doc(hidden)]
+pub mod testdata;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub use header::{parse_header, ComponentInfo, HeaderError, ImageHeader, Jp2Info, ProgressionOrder, EOC, SOT};
+pub use layout::{bytes_for_discard_level, SOD};
+pub use limits::ResourceLimits;
+pub use options::DecoderOptions;
+pub use pool::{DecodeResult, DecoderPool, DecoderPoolConfig};
+pub use protocol::{DecodeRequest, RequestId, StatsReply};
+pub use queue::RequestQueue;
+pub use process::{default_decoder_path, DecodedImage, DecoderError, DecoderProcess};
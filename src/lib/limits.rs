@@ -0,0 +1,116 @@
+//! # limits.rs  -- resource limits for the decoder process.
+//
+//  Animats
+//  March, 2023
+//
+//  A malicious or broken image can make the decoder allocate huge
+//  amounts of memory or compute forever. Operating system resource
+//  limits stop that. Exceeding a limit kills the decoder process,
+//  which looks like a crash to the library, which starts a new one.
+//
+//  Unix only. On other platforms, limits cannot be applied.
+//
+use std::process::Command;
+
+/// Resource limits for a decoder process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Maximum address space, in bytes. None means no limit.
+    pub memory_bytes: Option<u64>,
+    /// Maximum CPU time, in seconds. None means no limit.
+    /// This is total CPU time over the life of the process, not per request.
+    pub cpu_seconds: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Apply the limits to the current process.
+    #[cfg(unix)]
+    pub fn apply(&self) -> Result<(), std::io::Error> {
+        if let Some(memory_bytes) = self.memory_bytes {
+            set_limit(libc::RLIMIT_AS as i32, memory_bytes)?;
+        }
+        if let Some(cpu_seconds) = self.cpu_seconds {
+            set_limit(libc::RLIMIT_CPU as i32, cpu_seconds)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the limits to the current process.
+    #[cfg(not(unix))]
+    pub fn apply(&self) -> Result<(), std::io::Error> {
+        if *self == ResourceLimits::default() {
+            Ok(()) // no limits, nothing to do
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Resource limits are not supported on this platform",
+            ))
+        }
+    }
+
+    /// Arrange for the limits to be applied to a command's process when it is started.
+    #[cfg(unix)]
+    pub fn apply_to_command(&self, command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        if *self == ResourceLimits::default() {
+            return; // no limits, nothing to do
+        }
+        let limits = *self;
+        //  Safety: runs in the child between fork and exec. setrlimit is
+        //  async-signal-safe, and apply does not allocate.
+        unsafe {
+            command.pre_exec(move || limits.apply());
+        }
+    }
+
+    /// Arrange for the limits to be applied to a command's process when it is started.
+    /// Not supported on this platform, so limits are ignored.
+    #[cfg(not(unix))]
+    pub fn apply_to_command(&self, _command: &mut Command) {}
+}
+
+/// Set one resource limit, both soft and hard.
+//  The type of the resource number varies by platform, so it is passed as an i32.
+#[cfg(unix)]
+fn set_limit(resource: i32, value: u64) -> Result<(), std::io::Error> {
+    let limit = libc::rlimit {
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    //  Safety: plain system call with a valid pointer.
+    if unsafe { libc::setrlimit(resource as _, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_applied_to_command() {
+        const MEMORY_BYTES: u64 = 1024 * 1024 * 1024;
+        const CPU_SECONDS: u64 = 100;
+        let limits = ResourceLimits {
+            memory_bytes: Some(MEMORY_BYTES),
+            cpu_seconds: Some(CPU_SECONDS),
+        };
+        let mut command = Command::new("/bin/cat");
+        command.arg("/proc/self/limits");
+        limits.apply_to_command(&mut command);
+        let output = command.output().expect("Unable to run cat");
+        let text = String::from_utf8_lossy(&output.stdout);
+        //  Lines look like "Max address space   1073741824   1073741824   bytes"
+        let limit_line = |name: &str| {
+            text.lines()
+                .find(|line| line.starts_with(name))
+                .unwrap_or_else(|| panic!("No {} in {}", name, text))
+                .split_whitespace()
+                .filter_map(|word| word.parse::<u64>().ok())
+                .collect::<Vec<u64>>()
+        };
+        assert_eq!(limit_line("Max address space"), vec![MEMORY_BYTES, MEMORY_BYTES]);
+        assert_eq!(limit_line("Max cpu time"), vec![CPU_SECONDS, CPU_SECONDS]);
+    }
+}
@@ -0,0 +1,105 @@
+//! # options.rs  -- options for the decoder process.
+//
+//  Animats
+//  March, 2023
+//
+//  The library runs the decoder executable in LLSD mode. Everything
+//  else the executable can be told is passed on its command line,
+//  from here. Options not set get the executable's defaults.
+//
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// Command line options for a decoder process. None means the decoder's default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecoderOptions {
+    /// User agent for HTTP requests.
+    pub user_agent: Option<String>,
+    /// Connections to keep open to each server.
+    pub max_connections: Option<usize>,
+    /// Network timeout, seconds.
+    pub network_timeout_seconds: Option<u64>,
+    /// Retries of a failed fetch.
+    pub retries: Option<u32>,
+    /// Wait before the first retry, milliseconds. Doubles for each retry after that.
+    pub retry_delay_milliseconds: Option<u64>,
+    /// Longest wait before a retry, seconds.
+    pub retry_max_delay_seconds: Option<u64>,
+    /// Directory for a disk cache of fetched image bytes.
+    /// Several decoder processes can share one directory.
+    pub cache_dir: Option<PathBuf>,
+    /// Size limit for the disk cache, megabytes.
+    pub cache_megabytes: Option<u64>,
+    /// Size limit for decoded images kept in memory, megabytes. Each process has its own.
+    pub image_cache_megabytes: Option<u64>,
+    /// File of learned compression statistics.
+    pub stats_file: Option<PathBuf>,
+    /// Run in a seccomp sandbox, with no network or file access. Linux only.
+    /// Only requests with data work. Cannot be used with `cache_dir` or `stats_file`.
+    pub sandbox: bool,
+    /// Verbose logging, to the caller's standard error.
+    pub verbose: bool,
+}
+
+impl DecoderOptions {
+    /// Check for options which cannot be used together.
+    pub fn check(&self) -> Result<(), String> {
+        if self.sandbox && (self.cache_dir.is_some() || self.stats_file.is_some()) {
+            return Err("The disk cache and statistics file cannot be used in sandbox mode".to_string());
+        }
+        Ok(())
+    }
+
+    /// The command line arguments, not including "--llsd".
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        let mut arg = |name: &str, value: Option<OsString>| {
+            if let Some(value) = value {
+                args.push(name.into());
+                args.push(value);
+            }
+        };
+        let number = |n: Option<u64>| n.map(|n| n.to_string().into());
+        arg("--user-agent", self.user_agent.as_ref().map(|s| s.into()));
+        arg("--max-connections", number(self.max_connections.map(|n| n as u64)));
+        arg("--timeout", number(self.network_timeout_seconds));
+        arg("--retries", number(self.retries.map(u64::from)));
+        arg("--retry-delay", number(self.retry_delay_milliseconds));
+        arg("--retry-max-delay", number(self.retry_max_delay_seconds));
+        arg("--cache-dir", self.cache_dir.as_ref().map(|p| p.into()));
+        arg("--cache-size", number(self.cache_megabytes));
+        arg("--image-cache-size", number(self.image_cache_megabytes));
+        arg("--stats-file", self.stats_file.as_ref().map(|p| p.into()));
+        if self.sandbox {
+            args.push("--sandbox".into());
+        }
+        if self.verbose {
+            args.push("--verbose".into());
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder_options() {
+        assert!(DecoderOptions::default().to_args().is_empty());
+        let options = DecoderOptions {
+            user_agent: Some("Test agent".to_string()),
+            retries: Some(0),
+            cache_dir: Some(PathBuf::from("/tmp/cache")),
+            sandbox: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            options.to_args(),
+            ["--user-agent", "Test agent", "--retries", "0", "--cache-dir", "/tmp/cache", "--sandbox"]
+                .map(OsString::from)
+        );
+        assert!(options.check().is_err());
+        assert!(DecoderOptions { cache_dir: None, ..options }.check().is_ok());
+    }
+}
@@ -0,0 +1,355 @@
+//! # pool.rs  -- pool of decoder subprocesses.
+//
+//  Animats
+//  March, 2023
+//
+//  Each decoder subprocess handles one image at a time, so
+//  we run several and hand requests to whichever is idle.
+//  Requests wait in a priority queue. Results come back on a channel.
+//
+use crate::limits::ResourceLimits;
+use crate::options::DecoderOptions;
+use crate::process::{default_decoder_path, DecodedImage, DecoderError, DecoderProcess};
+use crate::protocol::{DecodeRequest, RequestId, StatsReply, MAX_REQUEST_ID};
+use crate::queue::RequestQueue;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Pool configuration.
+#[derive(Debug, Clone)]
+pub struct DecoderPoolConfig {
+    /// Number of decoder subprocesses.
+    pub pool_size: usize,
+    /// Maximum number of requests waiting for a decoder.
+    pub max_queue: usize,
+    /// Path to the decoder executable.
+    pub decoder_path: PathBuf,
+    /// Time limit for requests which do not have their own. None means no limit.
+    pub timeout: Option<Duration>,
+    /// Resource limits for each subprocess. Unix only.
+    pub limits: ResourceLimits,
+    /// Command line options for each subprocess.
+    pub options: DecoderOptions,
+}
+
+impl Default for DecoderPoolConfig {
+    fn default() -> Self {
+        DecoderPoolConfig {
+            pool_size: 4,
+            max_queue: 10000,
+            decoder_path: default_decoder_path(),
+            timeout: Some(Duration::from_secs(60)),
+            limits: ResourceLimits {
+                memory_bytes: Some(4 * 1024 * 1024 * 1024), // enough for an 8192 x 8192 RGBA image
+                cpu_seconds: None, // would be cumulative over many requests
+            },
+            options: DecoderOptions::default(),
+        }
+    }
+}
+
+/// Result of one request, as returned on the result channel.
+#[derive(Debug)]
+pub struct DecodeResult {
+    /// Id assigned by submit
+    pub id: RequestId,
+    /// URL of the request
+    pub url: String,
+    /// The image, or what went wrong
+    pub result: Result<DecodedImage, DecoderError>,
+}
+
+/// Queue and shutdown flag, shared with the worker threads.
+#[derive(Default)]
+struct PoolState {
+    /// Waiting requests. Highest priority first, then oldest first.
+    queue: RequestQueue,
+    /// Next request id
+    next_id: RequestId,
+    /// Workers exit when set
+    shutting_down: bool,
+}
+
+/// Shared between the pool and its workers.
+#[derive(Default)]
+struct PoolShared {
+    state: Mutex<PoolState>,
+    /// Signalled when work arrives or on shutdown.
+    work_available: Condvar,
+    /// Decoded image cache statistics of each worker's subprocess, as of its last request.
+    cache_stats: Mutex<Vec<StatsReply>>,
+}
+
+/// A pool of decoder subprocesses.
+pub struct DecoderPool {
+    /// Configuration
+    config: DecoderPoolConfig,
+    /// Queue shared with workers
+    shared: Arc<PoolShared>,
+    /// Worker threads, one per subprocess
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl DecoderPool {
+    /// Start the decoder subprocesses.
+    /// Results of requests come back on the returned channel.
+    pub fn new(
+        config: DecoderPoolConfig,
+    ) -> Result<(DecoderPool, Receiver<DecodeResult>), DecoderError> {
+        let (result_tx, result_rx) = channel();
+        let shared = Arc::new(PoolShared::default());
+        let mut pool = DecoderPool {
+            config,
+            shared,
+            workers: Vec::new(),
+        };
+        //  Start all the subprocesses first, so a bad path is reported here.
+        let mut decoders = Vec::new();
+        for _ in 0..pool.config.pool_size.max(1) {
+            let mut decoder = DecoderProcess::new_with_options(
+                &pool.config.decoder_path,
+                &pool.config.limits,
+                &pool.config.options,
+            )?;
+            decoder.set_timeout(pool.config.timeout);
+            decoders.push(decoder);
+        }
+        *pool.shared.cache_stats.lock().unwrap() = vec![StatsReply::default(); decoders.len()];
+        for (index, decoder) in decoders.into_iter().enumerate() {
+            let shared = Arc::clone(&pool.shared);
+            let result_tx = result_tx.clone();
+            pool.workers
+                .push(std::thread::spawn(move || worker(index, decoder, shared, result_tx)));
+        }
+        Ok((pool, result_rx))
+    }
+
+    /// Queue a request. Requests with higher `priority` are done first.
+    /// The pool assigns the request id, ignoring any id in the request.
+    /// Fails with `DecoderError::QueueFull` if too many requests are waiting.
+    ///
+    /// Images held for upgrades are per subprocess, and a request with `upgrade` set
+    /// goes to any idle subprocess. If that one does not have the image, it starts from the beginning.
+    pub fn submit(&self, mut request: DecodeRequest) -> Result<RequestId, DecoderError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.queue.len() >= self.config.max_queue {
+            return Err(DecoderError::QueueFull);
+        }
+        let id = state.next_id;
+        state.next_id = if id >= MAX_REQUEST_ID { 0 } else { id + 1 };
+        request.id = id;
+        state.queue.push(request);
+        self.shared.work_available.notify_one();
+        Ok(id)
+    }
+
+    /// Drop a request which has not yet been started.
+    /// Returns false if the request is not in the queue, because it
+    /// has already started or finished. No result is sent for a cancelled request.
+    pub fn cancel(&self, id: RequestId) -> bool {
+        self.shared.state.lock().unwrap().queue.cancel(id).is_some()
+    }
+
+    /// Change the priority of a request which has not yet been started.
+    /// Returns false if the request is not in the queue.
+    pub fn set_priority(&self, id: RequestId, priority: i32) -> bool {
+        self.shared.state.lock().unwrap().queue.set_priority(id, priority)
+    }
+
+    /// Number of requests waiting for a decoder.
+    pub fn queue_len(&self) -> usize {
+        self.shared.state.lock().unwrap().queue.len()
+    }
+
+    /// Decoded image cache statistics, totalled over all the subprocesses.
+    /// Each subprocess has its own cache. Its counts are as of its last finished
+    /// request, and start over if it is restarted.
+    pub fn cache_stats(&self) -> StatsReply {
+        self.shared
+            .cache_stats
+            .lock()
+            .unwrap()
+            .iter()
+            .fold(StatsReply::default(), |total, stats| StatsReply {
+                id: 0,
+                hits: total.hits.saturating_add(stats.hits),
+                misses: total.misses.saturating_add(stats.misses),
+                images: total.images.saturating_add(stats.images),
+                bytes: total.bytes.saturating_add(stats.bytes),
+            })
+    }
+}
+
+impl Drop for DecoderPool {
+    /// Stop the workers and their subprocesses. Requests still queued are dropped.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutting_down = true;
+        self.shared.work_available.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Worker thread. Takes requests from the queue and runs them on its decoder.
+/// After each one, records the decoder's cache statistics, as sent with the reply, in its slot, index.
+fn worker(
+    index: usize,
+    mut decoder: DecoderProcess,
+    shared: Arc<PoolShared>,
+    result_tx: Sender<DecodeResult>,
+) {
+    loop {
+        let request = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if state.shutting_down {
+                    return;
+                }
+                if let Some(request) = state.queue.pop() {
+                    break request;
+                }
+                state = shared.work_available.wait(state).unwrap();
+            }
+        };
+        let result = decoder.decode_request(&request);
+        if let Some(stats) = decoder.last_cache_stats() {
+            shared.cache_stats.lock().unwrap()[index] = stats.clone();
+        }
+        if result_tx
+            .send(DecodeResult {
+                id: request.id,
+                url: request.url,
+                result,
+            })
+            .is_err()
+        {
+            return; // nobody listening for results
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A request the decoder will reject immediately, without network access.
+    fn bad_request() -> DecodeRequest {
+        DecodeRequest {
+            url: "http://www.example.com/file.j2k".to_string(),
+            max_size: Some(64),
+            discard: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_decoder_pool() {
+        let config = DecoderPoolConfig {
+            pool_size: 2,
+            ..Default::default()
+        };
+        let (pool, results) = DecoderPool::new(config).expect("Unable to start pool");
+        let mut ids: Vec<RequestId> = (0..10)
+            .map(|n| {
+                pool.submit(DecodeRequest {
+                    priority: n,
+                    ..bad_request()
+                })
+                .unwrap()
+            })
+            .collect();
+        let mut result_ids: Vec<RequestId> = (0..10)
+            .map(|_| {
+                let result = results.recv().unwrap();
+                assert!(matches!(result.result, Err(DecoderError::Decode(_))));
+                result.id
+            })
+            .collect();
+        ids.sort();
+        result_ids.sort();
+        assert_eq!(ids, result_ids);
+    }
+
+    #[test]
+    fn test_decoder_pool_cache_stats() {
+        //  Nothing listens on this port, so every fetch fails at once. Each is a cache miss.
+        let config = DecoderPoolConfig {
+            pool_size: 2,
+            options: DecoderOptions {
+                retries: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (pool, results) = DecoderPool::new(config).expect("Unable to start pool");
+        for _ in 0..3 {
+            pool.submit(DecodeRequest {
+                url: "http://127.0.0.1:1/file.j2k".to_string(),
+                max_size: Some(64),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        for _ in 0..3 {
+            assert!(matches!(results.recv().unwrap().result, Err(DecoderError::Decode(_))));
+        }
+        let stats = pool.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.images), (0, 3, 0));
+    }
+
+    #[test]
+    fn test_decoder_pool_queue_full() {
+        //  A server which accepts connections but never answers keeps the only worker busy.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stuck_request = DecodeRequest {
+            url: format!("http://{}/file.j2k", listener.local_addr().unwrap()),
+            max_size: Some(64),
+            ..Default::default()
+        };
+        let config = DecoderPoolConfig {
+            pool_size: 1,
+            max_queue: 2,
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let (pool, results) = DecoderPool::new(config).expect("Unable to start pool");
+        let stuck = pool.submit(stuck_request).unwrap();
+        while pool.queue_len() > 0 {
+            std::thread::sleep(Duration::from_millis(10)); // until the worker has it
+        }
+        let queued: Vec<RequestId> = (0..2).map(|_| pool.submit(bad_request()).unwrap()).collect();
+        assert!(matches!(pool.submit(bad_request()), Err(DecoderError::QueueFull)));
+        for id in queued {
+            assert!(pool.cancel(id)); // still waiting
+        }
+        let result = results.recv().unwrap();
+        assert_eq!(result.id, stuck);
+        assert!(matches!(result.result, Err(DecoderError::Timeout)));
+    }
+
+    #[test]
+    fn test_decoder_pool_cancel() {
+        let config = DecoderPoolConfig {
+            pool_size: 1,
+            ..Default::default()
+        };
+        let (pool, results) = DecoderPool::new(config).expect("Unable to start pool");
+        //  The worker takes requests as we go, so some cancels succeed and some are too late.
+        let ids: Vec<RequestId> = (0..20).map(|_| pool.submit(bad_request()).unwrap()).collect();
+        let last = *ids.last().unwrap();
+        let cancelled = ids.iter().filter(|id| **id != last && pool.cancel(**id)).count();
+        assert!(!pool.cancel(12345)); // not queued
+        //  Everything not cancelled comes back.
+        for _ in 0..(ids.len() - cancelled) {
+            let result = results.recv().unwrap();
+            assert!(ids.contains(&result.id));
+        }
+        assert!(results
+            .recv_timeout(std::time::Duration::from_millis(200))
+            .is_err());
+    }
+}
@@ -0,0 +1,647 @@
+//! # process.rs  -- run the decoder executable as a subprocess.
+//
+//  Animats
+//  March, 2023
+//
+//  The decoder runs in a separate process, so that a crash in the
+//  C decoder cannot take down the caller.
+//
+use crate::limits::ResourceLimits;
+use crate::options::DecoderOptions;
+use crate::protocol::{read_message, write_message, Command as DecoderCommand, DecodeReply, DecodeRequest, StatsReply};
+use serde_llsd::LLSDValue;
+use std::collections::HashMap;
+use std::convert;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Name of the decoder executable.
+const DECODER_PROGRAM_NAME: &str = "jpeg2000_decoder";
+
+/// Things that can go wrong with decoding an image.
+#[derive(Debug)]
+pub enum DecoderError {
+    /// Unable to start or communicate with the decoder subprocess.
+    Io(std::io::Error),
+    /// Decoder subprocess sent something we cannot understand.
+    Protocol(String),
+    /// Decoder reported an error for this image. Network, decode, or content errors.
+    Decode(String),
+    /// Decoder subprocess died or stopped responding while working on this request.
+    /// It will be restarted for the next request.
+    Crashed(String),
+    /// Request ran over its time limit. The decoder subprocess was stopped,
+    /// and will be restarted for the next request.
+    Timeout,
+    /// Too many requests waiting in the pool queue.
+    QueueFull,
+    /// Request was cancelled before decoding started.
+    Cancelled,
+}
+
+impl std::fmt::Display for DecoderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecoderError::Io(e) => write!(f, "Decoder process I/O error: {}", e),
+            DecoderError::Protocol(s) => write!(f, "Decoder protocol error: {}", s),
+            DecoderError::Decode(s) => write!(f, "{}", s),
+            DecoderError::Crashed(s) => write!(f, "Decoder process crashed: {}", s),
+            DecoderError::Timeout => write!(f, "Decoder timed out"),
+            DecoderError::QueueFull => write!(f, "Decoder queue full"),
+            DecoderError::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for DecoderError {}
+
+//
+//  Encapsulate errors from each of the lower level error types
+//
+impl convert::From<std::io::Error> for DecoderError {
+    fn from(err: std::io::Error) -> DecoderError {
+        DecoderError::Io(err)
+    }
+}
+
+/// A decoded image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedImage {
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Bytes per pixel. 3 for RGB, 4 for RGBA.
+    pub depth: u8,
+    /// Discard level. 0 is full size, 1 halves each dimension, etc.
+    pub discard: u32,
+    /// Discard level the image actually has detail for. Higher than `discard`
+    /// if the file was too short, and the image is blurry.
+    /// None if not known, because the file's layout doesn't say and only part of it was read.
+    pub effective_discard: Option<u32>,
+    /// Raw pixels, no headers, size width * height * depth
+    pub pixels: Vec<u8>,
+}
+
+/// Find the decoder executable.
+///
+/// Looks in the directory of the current executable, and its parent
+/// (test programs run from target/debug/deps), then falls back to the search path.
+pub fn default_decoder_path() -> PathBuf {
+    let program_name = format!("{}{}", DECODER_PROGRAM_NAME, std::env::consts::EXE_SUFFIX);
+    if let Ok(exe) = std::env::current_exe() {
+        for dir in exe.ancestors().skip(1).take(2) {
+            let candidate = dir.join(&program_name);
+            if candidate.is_file() {
+                return candidate;
+            }
+        }
+    }
+    PathBuf::from(program_name)
+}
+
+/// First wait before restarting a crashed decoder.
+const RESTART_DELAY_MIN: Duration = Duration::from_millis(100);
+/// Longest wait before restarting a crashed decoder.
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(30);
+/// Time an idle subprocess gets to exit by itself when shut down, so it can save what it learned.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+/// Extra time we allow beyond the time limit sent to the subprocess.
+/// The subprocess enforces its own time limit, which is cleaner, so we give it a chance to do so.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Why a request to a subprocess got no usable reply.
+enum CallError {
+    /// No reply in time.
+    Timeout,
+    /// Subprocess died or is out of sync.
+    Failed(String),
+}
+
+/// A message as read by the reader thread, or why it could not be read.
+type ReadResult = Result<HashMap<String, LLSDValue>, String>;
+
+/// A running decoder subprocess.
+struct ChildProcess {
+    /// The subprocess
+    child: Child,
+    /// Requests go here
+    stdin: BufWriter<ChildStdin>,
+    /// Replies, from the reader thread. Closes when the subprocess closes its output.
+    replies: Receiver<ReadResult>,
+}
+
+impl ChildProcess {
+    /// Start the decoder executable at the given path, with resource limits and options.
+    fn new(
+        decoder_path: &Path,
+        limits: &ResourceLimits,
+        options: &DecoderOptions,
+    ) -> Result<ChildProcess, std::io::Error> {
+        let mut command = Command::new(decoder_path);
+        command
+            .arg("--llsd")
+            .args(options.to_args())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit()); // verbose output and errors go to our stderr
+        limits.apply_to_command(&mut command);
+        let mut child = command.spawn()?;
+        let stdin = BufWriter::new(child.stdin.take().expect("No stdin for decoder process"));
+        let mut stdout = BufReader::new(child.stdout.take().expect("No stdout for decoder process"));
+        //  Replies are read in a separate thread, so we can stop waiting at a deadline.
+        let (reply_tx, replies) = channel();
+        std::thread::spawn(move || loop {
+            match read_message(&mut stdout) {
+                Ok(Some(msg)) => {
+                    if reply_tx.send(Ok(msg)).is_err() {
+                        break; // nobody listening
+                    }
+                }
+                Ok(None) => break, // end of file, subprocess is gone
+                Err(e) => {
+                    let _ = reply_tx.send(Err(format!("Error reading reply: {:?}", e)));
+                    break;
+                }
+            }
+        });
+        Ok(ChildProcess {
+            child,
+            stdin,
+            replies,
+        })
+    }
+
+    /// Send a request and wait for the reply, for no longer than the timeout.
+    /// Any error here means the subprocess is dead, stuck, or out of sync, and must be replaced.
+    fn call(
+        &mut self,
+        request: &DecodeRequest,
+        timeout: Option<Duration>,
+    ) -> Result<DecodeReply, CallError> {
+        let msg = self.exchange(&request.to_llsd(), timeout)?;
+        let reply = DecodeReply::from_llsd(&msg).map_err(|e| CallError::Failed(format!("{:?}", e)))?;
+        if reply.id != request.id || reply.url != request.url {
+            return Err(CallError::Failed(format!(
+                "Reply for #{} {} when expecting reply for #{} {}",
+                reply.id, reply.url, request.id, request.url
+            )));
+        }
+        Ok(reply)
+    }
+
+    /// Ask for cache statistics, and wait for the reply, for no longer than the timeout.
+    fn call_stats(&mut self, timeout: Option<Duration>) -> Result<StatsReply, CallError> {
+        let msg = self.exchange(&DecoderCommand::Stats(0).to_llsd(), timeout)?;
+        StatsReply::from_llsd(&msg).map_err(|e| CallError::Failed(format!("{:?}", e)))
+    }
+
+    /// Send a message and wait for the next one back, for no longer than the timeout.
+    fn exchange(
+        &mut self,
+        msg: &HashMap<String, LLSDValue>,
+        timeout: Option<Duration>,
+    ) -> Result<HashMap<String, LLSDValue>, CallError> {
+        write_message(&mut self.stdin, msg)
+            .map_err(|e| CallError::Failed(format!("Error sending request: {:?}", e)))?;
+        let closed = || CallError::Failed("Decoder process closed its output".to_string());
+        let received = match timeout {
+            Some(timeout) => self.replies.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => CallError::Timeout,
+                RecvTimeoutError::Disconnected => closed(),
+            }),
+            None => self.replies.recv().map_err(|_| closed()),
+        };
+        received?.map_err(CallError::Failed)
+    }
+
+    /// Kill the subprocess if still running, and report how it ended.
+    fn shut_down(mut self) -> String {
+        end_child(&mut self.child)
+    }
+
+    /// Close the input of an idle subprocess, which tells it to exit, and wait a short time for that.
+    /// Kill it if it does not.
+    fn close(mut self) -> String {
+        drop(self.stdin);
+        let start = Instant::now();
+        while start.elapsed() < SHUTDOWN_GRACE {
+            if let Ok(Some(_)) | Err(_) = self.child.try_wait() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        end_child(&mut self.child)
+    }
+}
+
+/// Kill a subprocess if still running, and report how it ended.
+fn end_child(child: &mut Child) -> String {
+    //  If it already exited, report that status, not our kill.
+    let status = match child.try_wait() {
+        Ok(Some(status)) => Ok(status),
+        _ => {
+            let _ = child.kill();
+            child.wait()
+        }
+    };
+    match status {
+        Ok(status) => status.to_string(),
+        Err(e) => format!("unknown status: {}", e),
+    }
+}
+
+/// A decoder subprocess, in LLSD mode.
+/// Requests are handled one at a time.
+///
+/// If the subprocess dies, the request in progress fails with
+/// `DecoderError::Crashed`, and a new subprocess is started for the next request.
+/// If a request runs over its time limit, the subprocess is stopped, and
+/// the request fails with `DecoderError::Timeout`.
+/// Repeated crashes or timeouts cause increasing delays before restarting.
+///
+/// Exceeding a resource limit kills the subprocess, and is reported as a crash.
+pub struct DecoderProcess {
+    /// Path to the decoder executable, for restarts.
+    decoder_path: PathBuf,
+    /// Resource limits for the subprocess.
+    limits: ResourceLimits,
+    /// Command line options for the subprocess.
+    options: DecoderOptions,
+    /// The subprocess, if running.
+    child_opt: Option<ChildProcess>,
+    /// Time limit for requests which do not have their own.
+    timeout: Option<Duration>,
+    /// Total crashes since creation.
+    crash_count: u32,
+    /// Total timeouts since creation.
+    timeout_count: u32,
+    /// Crashes and timeouts since the last good reply. Controls restart delay.
+    consecutive_crashes: u32,
+    /// Time of last crash.
+    last_crash: Option<Instant>,
+    /// Cache statistics sent with the last reply from the running subprocess.
+    last_cache_stats: Option<StatsReply>,
+}
+
+impl DecoderProcess {
+    /// Start the decoder executable at the given path.
+    pub fn new(decoder_path: &Path) -> Result<DecoderProcess, DecoderError> {
+        DecoderProcess::new_with_limits(decoder_path, &ResourceLimits::default())
+    }
+
+    /// Start the decoder executable at the given path, with resource limits.
+    /// Limits apply only on Unix-like systems, and are ignored elsewhere.
+    pub fn new_with_limits(
+        decoder_path: &Path,
+        limits: &ResourceLimits,
+    ) -> Result<DecoderProcess, DecoderError> {
+        DecoderProcess::new_with_options(decoder_path, limits, &DecoderOptions::default())
+    }
+
+    /// Start the decoder executable at the given path, with resource limits and command line options.
+    /// The same options are used when the subprocess is restarted.
+    pub fn new_with_options(
+        decoder_path: &Path,
+        limits: &ResourceLimits,
+        options: &DecoderOptions,
+    ) -> Result<DecoderProcess, DecoderError> {
+        options
+            .check()
+            .map_err(|msg| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg))?;
+        Ok(DecoderProcess {
+            decoder_path: decoder_path.to_path_buf(),
+            limits: *limits,
+            options: options.clone(),
+            child_opt: Some(ChildProcess::new(decoder_path, limits, options)?),
+            timeout: None,
+            crash_count: 0,
+            timeout_count: 0,
+            consecutive_crashes: 0,
+            last_crash: None,
+            last_cache_stats: None,
+        })
+    }
+
+    /// Decode one image.
+    ///
+    /// Specify either max_size or discard, but not both.
+    /// If neither is specified, the full size image is returned.
+    pub fn decode(
+        &mut self,
+        url: &str,
+        max_size: Option<u32>,
+        discard: Option<u32>,
+    ) -> Result<DecodedImage, DecoderError> {
+        self.decode_request(&DecodeRequest {
+            url: url.to_string(),
+            max_size,
+            discard,
+            ..Default::default()
+        })
+    }
+
+    /// Decode an image again at a higher resolution, after an earlier `decode` of the same URL.
+    /// The decoder subprocess reuses the data it already fetched, and fetches only the rest.
+    /// If it no longer has the image, such as after a restart, this is the same as `decode`.
+    pub fn upgrade(
+        &mut self,
+        url: &str,
+        max_size: Option<u32>,
+        discard: Option<u32>,
+    ) -> Result<DecodedImage, DecoderError> {
+        self.decode_request(&DecodeRequest {
+            url: url.to_string(),
+            max_size,
+            discard,
+            upgrade: true,
+            ..Default::default()
+        })
+    }
+
+    /// Decode one image from JPEG 2000 data the caller already has,
+    /// such as from a cache. The decoder does no network access.
+    ///
+    /// Specify either max_size or discard, but not both.
+    /// If neither is specified, the full size image is returned.
+    pub fn decode_data(
+        &mut self,
+        data: &[u8],
+        max_size: Option<u32>,
+        discard: Option<u32>,
+    ) -> Result<DecodedImage, DecoderError> {
+        self.decode_request(&DecodeRequest {
+            data: Some(data.to_vec()),
+            max_size,
+            discard,
+            ..Default::default()
+        })
+    }
+
+    /// Decode one image, as specified by a request.
+    /// The time limit is from the request, or, if none, the one set with `set_timeout`.
+    pub fn decode_request(&mut self, request: &DecodeRequest) -> Result<DecodedImage, DecoderError> {
+        self.restart_if_needed()?;
+        let mut request = request.clone();
+        if request.timeout_ms.is_none() {
+            request.timeout_ms = self
+                .timeout
+                .map(|t| t.as_millis().clamp(1, i32::MAX as u128) as u32); // subprocess enforces this too
+        }
+        let timeout = request
+            .timeout_ms
+            .map(|ms| Duration::from_millis(ms.into()) + TIMEOUT_GRACE);
+        let child = self.child_opt.as_mut().expect("Decoder not running");
+        let reply = match child.call(&request, timeout) {
+            Ok(reply) => reply,
+            Err(CallError::Timeout) => {
+                self.stop_child();
+                self.timeout_count += 1;
+                return Err(DecoderError::Timeout);
+            }
+            Err(CallError::Failed(msg)) => {
+                let status = self.stop_child();
+                self.crash_count += 1;
+                return Err(DecoderError::Crashed(format!("{} ({})", msg, status)));
+            }
+        };
+        if reply.timed_out {
+            //  Subprocess enforced the time limit, and is exiting.
+            self.stop_child();
+            self.timeout_count += 1;
+            return Err(DecoderError::Timeout);
+        }
+        self.consecutive_crashes = 0; // subprocess is working
+        self.last_cache_stats = reply.cache_stats;
+        if reply.cancelled {
+            return Err(DecoderError::Cancelled);
+        }
+        if let Some(err) = reply.err {
+            return Err(DecoderError::Decode(err));
+        }
+        Ok(DecodedImage {
+            width: reply.w,
+            height: reply.h,
+            depth: reply.d,
+            discard: reply.discard,
+            effective_discard: reply.effective_discard,
+            pixels: reply.image,
+        })
+    }
+
+    /// Decoded image cache statistics from the decoder subprocess, since it was started,
+    /// as sent with the reply to the last request. None if it sent none. No message is sent to the subprocess.
+    /// A restarted subprocess starts over with an empty cache.
+    pub fn last_cache_stats(&self) -> Option<&StatsReply> {
+        self.last_cache_stats.as_ref()
+    }
+
+    /// Decoded image cache statistics from the decoder subprocess, since it was started.
+    /// A restarted subprocess starts over with an empty cache.
+    pub fn cache_stats(&mut self) -> Result<StatsReply, DecoderError> {
+        self.restart_if_needed()?;
+        let timeout = self.timeout.map(|t| t + TIMEOUT_GRACE);
+        let child = self.child_opt.as_mut().expect("Decoder not running");
+        match child.call_stats(timeout) {
+            Ok(stats) => Ok(stats),
+            Err(CallError::Timeout) => {
+                self.stop_child();
+                self.timeout_count += 1;
+                Err(DecoderError::Timeout)
+            }
+            Err(CallError::Failed(msg)) => {
+                let status = self.stop_child();
+                self.crash_count += 1;
+                Err(DecoderError::Crashed(format!("{} ({})", msg, status)))
+            }
+        }
+    }
+
+    /// Set the time limit for requests which do not have their own. None means no limit.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Number of times the subprocess has crashed.
+    pub fn crash_count(&self) -> u32 {
+        self.crash_count
+    }
+
+    /// Number of requests which ran out of time.
+    pub fn timeout_count(&self) -> u32 {
+        self.timeout_count
+    }
+
+    /// Stop a subprocess which crashed or is stuck. Returns how it ended.
+    fn stop_child(&mut self) -> String {
+        self.consecutive_crashes += 1;
+        self.last_crash = Some(Instant::now());
+        self.last_cache_stats = None; // a new subprocess starts over
+        match self.child_opt.take() {
+            Some(child) => child.shut_down(),
+            None => "not running".to_string(),
+        }
+    }
+
+    /// Start a new subprocess if the old one died.
+    /// Waits first, longer after each consecutive crash, so that a bad image
+    /// cannot cause a tight crash and restart loop.
+    fn restart_if_needed(&mut self) -> Result<(), DecoderError> {
+        if self.child_opt.is_some() {
+            return Ok(());
+        }
+        if let Some(last_crash) = self.last_crash {
+            let delay = restart_delay(self.consecutive_crashes);
+            let elapsed = last_crash.elapsed();
+            if elapsed < delay {
+                std::thread::sleep(delay - elapsed);
+            }
+        }
+        self.child_opt = Some(ChildProcess::new(&self.decoder_path, &self.limits, &self.options)?);
+        Ok(())
+    }
+}
+
+/// Delay before restart, after this many consecutive crashes.
+/// Doubles with each crash, up to a limit.
+fn restart_delay(consecutive_crashes: u32) -> Duration {
+    if consecutive_crashes == 0 {
+        return Duration::ZERO;
+    }
+    let doublings = (consecutive_crashes - 1).min(16);
+    (RESTART_DELAY_MIN * 2_u32.pow(doublings)).min(RESTART_DELAY_MAX)
+}
+
+impl Drop for DecoderProcess {
+    /// Shut down the subprocess. It is idle between requests, so it exits as soon as its input closes.
+    fn drop(&mut self) {
+        if let Some(child) = self.child_opt.take() {
+            child.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder_process() {
+        //  No such program
+        assert!(matches!(
+            DecoderProcess::new(Path::new("/nonexistent/jpeg2000_decoder")),
+            Err(DecoderError::Io(_))
+        ));
+        //  Errors are reported per request, and the process keeps running.
+        let mut decoder = DecoderProcess::new(&default_decoder_path()).expect("Unable to start decoder");
+        for _ in 0..2 {
+            match decoder.decode("http://www.example.com/file.j2k", Some(64), Some(1)) {
+                Err(DecoderError::Decode(s)) => assert!(s.contains("Bad request")),
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        }
+        //  Data which is not JPEG 2000 is a decode error, not a bad request.
+        match decoder.decode_data(&[0; 100], Some(64), None) {
+            Err(DecoderError::Decode(s)) => assert!(!s.contains("Bad request")),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(decoder.last_cache_stats(), Some(&StatsReply::default())); // sent with each reply
+        assert_eq!(decoder.cache_stats().unwrap(), StatsReply::default()); // nothing decoded from a URL
+        assert_eq!(decoder.crash_count(), 0);
+    }
+
+    #[test]
+    fn test_decoder_options() {
+        let options = DecoderOptions {
+            sandbox: true,
+            ..Default::default()
+        };
+        let mut decoder = DecoderProcess::new_with_options(&default_decoder_path(), &ResourceLimits::default(), &options)
+            .expect("Unable to start decoder");
+        for restart in [false, true] {
+            if restart {
+                //  Kill it. The next request fails, and the one after that gets a new subprocess.
+                decoder.child_opt.as_mut().unwrap().child.kill().unwrap();
+                assert!(matches!(decoder.decode("http://www.example.com/file.j2k", Some(64), None), Err(DecoderError::Crashed(_))));
+            }
+            match decoder.decode("http://www.example.com/file.j2k", Some(64), None) {
+                Err(DecoderError::Decode(s)) => assert!(s.contains("sandbox")), // still in the sandbox
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        }
+        //  Conflicting options
+        let options = DecoderOptions {
+            stats_file: Some(PathBuf::from("/tmp/stats")),
+            ..options
+        };
+        assert!(matches!(
+            DecoderProcess::new_with_options(&default_decoder_path(), &ResourceLimits::default(), &options),
+            Err(DecoderError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_decoder_crash_restart() {
+        //  A "decoder" which exits immediately looks like a crash on every request. Linux only.
+        let mut decoder = DecoderProcess::new(Path::new("/bin/true")).expect("Unable to start");
+        let start = Instant::now();
+        for n in 1..=3 {
+            match decoder.decode("http://www.example.com/file.j2k", Some(64), None) {
+                Err(DecoderError::Crashed(_)) => {}
+                r => panic!("Unexpected result: {:?}", r),
+            }
+            assert_eq!(decoder.crash_count(), n);
+        }
+        //  Two restarts, with backoff.
+        assert!(start.elapsed() >= restart_delay(1) + restart_delay(2));
+    }
+
+    #[test]
+    fn test_decoder_memory_limit() {
+        //  Far too little memory to run at all. Fails either at start or on the first request.
+        let limits = ResourceLimits {
+            memory_bytes: Some(1024 * 1024),
+            cpu_seconds: None,
+        };
+        match DecoderProcess::new_with_limits(&default_decoder_path(), &limits) {
+            Err(DecoderError::Io(_)) => {}
+            Ok(mut decoder) => match decoder.decode("http://www.example.com/file.j2k", Some(64), Some(1)) {
+                Err(DecoderError::Crashed(_)) => {}
+                r => panic!("Unexpected result: {:?}", r),
+            },
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_decoder_timeout() {
+        //  A server which accepts connections but never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.j2k", listener.local_addr().unwrap());
+        let mut decoder = DecoderProcess::new(&default_decoder_path()).expect("Unable to start decoder");
+        decoder.set_timeout(Some(Duration::from_millis(300)));
+        let start = Instant::now();
+        match decoder.decode(&url, Some(64), None) {
+            Err(DecoderError::Timeout) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(decoder.timeout_count(), 1);
+        //  Restarted for the next request.
+        match decoder.decode(&url, Some(64), Some(1)) {
+            Err(DecoderError::Decode(s)) => assert!(s.contains("Bad request")),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        assert_eq!(restart_delay(0), Duration::ZERO);
+        assert_eq!(restart_delay(1), RESTART_DELAY_MIN);
+        assert_eq!(restart_delay(2), RESTART_DELAY_MIN * 2);
+        assert_eq!(restart_delay(100), RESTART_DELAY_MAX);
+    }
+}
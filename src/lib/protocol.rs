@@ -0,0 +1,613 @@
+//! # protocol.rs  -- LLSD messages between the library and the decoder subprocess.
+//!
+//  Animats
+//  March, 2023
+//
+//! ## Framing
+//!
+//! Each message is one LLSD map in binary LLSD format, with its usual
+//! `<? LLSD/Binary ?>` header, preceded by the length of the LLSD bytes
+//! as a 4-byte big-endian unsigned integer.
+//! Requests go to the subprocess on its standard input, and replies come back on its standard output,
+//! one reply per request.
+//!
+//! The length prefix lets the reader detect a clean end of input and reject
+//! absurdly large messages before allocating memory for them.
+
+use anyhow::{anyhow, Error};
+use serde_llsd::LLSDValue;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// Largest message we will accept. Big enough for an 8192 x 8192 RGBA image plus overhead.
+pub const MAX_MESSAGE_SIZE: usize = 8192 * 8192 * 4 + 65536;
+
+/// Read one framed LLSD map.
+/// Returns None on a clean end of file before the start of a message.
+pub fn read_message(reader: &mut impl Read) -> Result<Option<HashMap<String, LLSDValue>>, Error> {
+    let mut len_bytes = [0u8; 4];
+    //  Distinguish clean EOF from a truncated length prefix.
+    let mut got = 0;
+    while got < len_bytes.len() {
+        let n = reader.read(&mut len_bytes[got..])?;
+        if n == 0 {
+            if got == 0 {
+                return Ok(None); // clean EOF
+            }
+            return Err(anyhow!("End of file in LLSD message length"));
+        }
+        got += n;
+    }
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("LLSD message length {} too large", len));
+    }
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    //  Binary LLSD prefix is required, then the value itself.
+    let prefix = serde_llsd::de::binary::LLSDBINARYPREFIX;
+    if !buf.starts_with(prefix) {
+        return Err(anyhow!("LLSD message does not start with binary LLSD header"));
+    }
+    match serde_llsd::from_bytes(&buf[prefix.len()..])? {
+        LLSDValue::Map(map) => Ok(Some(map)),
+        v => Err(anyhow!("LLSD message is not a map: {:?}", v)),
+    }
+}
+
+/// Write one framed LLSD map, and flush, so the other end sees it immediately.
+pub fn write_message(
+    writer: &mut impl Write,
+    msg: &HashMap<String, LLSDValue>,
+) -> Result<(), Error> {
+    let bytes = serde_llsd::to_bytes(&LLSDValue::Map(msg.clone()))?;
+    if bytes.len() > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("LLSD message length {} too large", bytes.len()));
+    }
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Get an optional non-negative integer field from a message.
+fn get_u32(msg: &HashMap<String, LLSDValue>, key: &str) -> Result<Option<u32>, Error> {
+    match msg.get(key) {
+        None => Ok(None),
+        Some(LLSDValue::Integer(v)) => {
+            if *v < 0 {
+                Err(anyhow!("Field \"{}\" is negative: {}", key, v))
+            } else {
+                Ok(Some(*v as u32))
+            }
+        }
+        Some(v) => Err(anyhow!("Field \"{}\" is not an integer: {:?}", key, v)),
+    }
+}
+
+/// Get an optional string field from a message. URIs are accepted as strings.
+fn get_string(msg: &HashMap<String, LLSDValue>, key: &str) -> Result<Option<String>, Error> {
+    match msg.get(key) {
+        None => Ok(None),
+        Some(LLSDValue::String(s)) | Some(LLSDValue::URI(s)) => Ok(Some(s.clone())),
+        Some(v) => Err(anyhow!("Field \"{}\" is not a string: {:?}", key, v)),
+    }
+}
+
+/// Identifies a request. Chosen by the requester, and returned in the reply.
+pub type RequestId = u32;
+
+/// Largest request id. Ids are sent as LLSD integers, which are signed.
+pub const MAX_REQUEST_ID: RequestId = i32::MAX as RequestId;
+
+/// Get an optional signed integer field from a message.
+fn get_i32(msg: &HashMap<String, LLSDValue>, key: &str) -> Result<Option<i32>, Error> {
+    match msg.get(key) {
+        None => Ok(None),
+        Some(LLSDValue::Integer(v)) => Ok(Some(*v)),
+        Some(v) => Err(anyhow!("Field \"{}\" is not an integer: {:?}", key, v)),
+    }
+}
+
+/// Command to the decoder subprocess.
+///
+/// The "cmd" field selects the command. If absent, the command is "decode".
+/// "upgrade" is a decode request for an image decoded before at a lower resolution.
+/// Decode requests wait in a queue, highest priority first, and each gets one reply.
+/// "cancel" and "priority" affect requests still in the queue, and get no reply of their own.
+/// A cancelled request gets a reply with "cancelled" set.
+/// "stats" is answered at once, ahead of any queued requests, with a `StatsReply`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Decode an image. Also "upgrade", with `upgrade` set in the request.
+    Decode(DecodeRequest),
+    /// Drop a queued request. Fields: "id".
+    Cancel(RequestId),
+    /// Change the priority of a queued request. Fields: "id", "priority".
+    SetPriority(RequestId, i32),
+    /// Report decoded image cache statistics. Fields: "id", optional.
+    Stats(RequestId),
+}
+
+impl Command {
+    /// Convert to LLSD for sending.
+    pub fn to_llsd(&self) -> HashMap<String, LLSDValue> {
+        match self {
+            Command::Decode(request) => request.to_llsd(),
+            Command::Cancel(id) => {
+                let mut msg = HashMap::new();
+                msg.insert("cmd".to_string(), LLSDValue::String("cancel".to_string()));
+                msg.insert("id".to_string(), LLSDValue::Integer(*id as i32));
+                msg
+            }
+            Command::SetPriority(id, priority) => {
+                let mut msg = HashMap::new();
+                msg.insert("cmd".to_string(), LLSDValue::String("priority".to_string()));
+                msg.insert("id".to_string(), LLSDValue::Integer(*id as i32));
+                msg.insert("priority".to_string(), LLSDValue::Integer(*priority));
+                msg
+            }
+            Command::Stats(id) => {
+                let mut msg = HashMap::new();
+                msg.insert("cmd".to_string(), LLSDValue::String("stats".to_string()));
+                msg.insert("id".to_string(), LLSDValue::Integer(*id as i32));
+                msg
+            }
+        }
+    }
+
+    /// Convert from received LLSD, with validation.
+    pub fn from_llsd(msg: &HashMap<String, LLSDValue>) -> Result<Command, Error> {
+        let id = || get_u32(msg, "id")?.ok_or_else(|| anyhow!("Command has no \"id\""));
+        match get_string(msg, "cmd")?.as_deref() {
+            None | Some("decode") | Some("upgrade") => {
+                Ok(Command::Decode(DecodeRequest::from_llsd(msg)?))
+            }
+            Some("cancel") => Ok(Command::Cancel(id()?)),
+            Some("priority") => {
+                let priority = get_i32(msg, "priority")?
+                    .ok_or_else(|| anyhow!("Command has no \"priority\""))?;
+                Ok(Command::SetPriority(id()?, priority))
+            }
+            Some("stats") => Ok(Command::Stats(get_u32(msg, "id")?.unwrap_or_default())),
+            Some(cmd) => Err(anyhow!("Unknown command \"{}\"", cmd)),
+        }
+    }
+}
+
+/// Request to the decoder subprocess.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecodeRequest {
+    /// Request id, returned in the reply. Needed only to cancel or reprioritize.
+    /// 0 if not sent. A request with the id of one still waiting is refused with an error reply.
+    pub id: RequestId,
+    /// Higher priority requests are done first. Default 0.
+    pub priority: i32,
+    /// URL of the JPEG 2000 image. Empty if `data` is given.
+    pub url: String,
+    /// The JPEG 2000 image itself, instead of a URL.
+    pub data: Option<Vec<u8>>,
+    /// Maximum size of returned image, largest dimension. Used to compute discard level.
+    pub max_size: Option<u32>,
+    /// Requested image discard level. 0 is full size, 1 halves each dimension, etc.
+    pub discard: Option<u32>,
+    /// Time limit for this request, in milliseconds. The decoder exits if it runs over.
+    pub timeout_ms: Option<u32>,
+    /// Upgrade an image decoded before at lower resolution, reusing the data
+    /// already fetched. If the decoder does not have it, this is an ordinary decode.
+    /// URL requests only.
+    pub upgrade: bool,
+}
+
+impl DecodeRequest {
+    /// Convert to LLSD for sending.
+    pub fn to_llsd(&self) -> HashMap<String, LLSDValue> {
+        let mut msg = HashMap::new();
+        if self.upgrade {
+            msg.insert("cmd".to_string(), LLSDValue::String("upgrade".to_string()));
+        }
+        msg.insert("id".to_string(), LLSDValue::Integer(self.id as i32));
+        msg.insert("priority".to_string(), LLSDValue::Integer(self.priority));
+        if !self.url.is_empty() || self.data.is_none() {
+            msg.insert("url".to_string(), LLSDValue::String(self.url.clone()));
+        }
+        if let Some(data) = &self.data {
+            msg.insert("data".to_string(), LLSDValue::Binary(data.clone()));
+        }
+        if let Some(max_size) = self.max_size {
+            msg.insert("maxsize".to_string(), LLSDValue::Integer(max_size as i32));
+        }
+        if let Some(discard) = self.discard {
+            msg.insert("discard".to_string(), LLSDValue::Integer(discard as i32));
+        }
+        if let Some(timeout_ms) = self.timeout_ms {
+            msg.insert("timeout_ms".to_string(), LLSDValue::Integer(timeout_ms.min(i32::MAX as u32) as i32));
+        }
+        msg
+    }
+
+    /// Convert from received LLSD, with validation.
+    pub fn from_llsd(msg: &HashMap<String, LLSDValue>) -> Result<DecodeRequest, Error> {
+        let url = get_string(msg, "url")?;
+        let data = match msg.get("data") {
+            None => None,
+            Some(LLSDValue::Binary(b)) => Some(b.clone()),
+            Some(v) => return Err(anyhow!("Field \"data\" is not binary: {:?}", v)),
+        };
+        let url = match (url, &data) {
+            (Some(_), Some(_)) => return Err(anyhow!("Specify either \"url\" or \"data\", not both")),
+            (None, None) => return Err(anyhow!("Request has no \"url\" or \"data\"")),
+            (url, _) => url.unwrap_or_default(),
+        };
+        let max_size = get_u32(msg, "maxsize")?;
+        let discard = get_u32(msg, "discard")?;
+        if max_size.is_some() && discard.is_some() {
+            return Err(anyhow!("Specify either \"maxsize\" or \"discard\", not both"));
+        }
+        if max_size == Some(0) {
+            return Err(anyhow!("\"maxsize\" must be at least 1"));
+        }
+        let timeout_ms = get_u32(msg, "timeout_ms")?;
+        if timeout_ms == Some(0) {
+            return Err(anyhow!("\"timeout_ms\" must be at least 1"));
+        }
+        let upgrade = get_string(msg, "cmd")?.as_deref() == Some("upgrade");
+        if upgrade && data.is_some() {
+            return Err(anyhow!("\"upgrade\" needs a \"url\", not \"data\""));
+        }
+        Ok(DecodeRequest {
+            id: get_u32(msg, "id")?.unwrap_or_default(),
+            priority: get_i32(msg, "priority")?.unwrap_or_default(),
+            url,
+            data,
+            max_size,
+            discard,
+            timeout_ms,
+            upgrade,
+        })
+    }
+}
+
+/// Reply from the decoder subprocess.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DecodeReply {
+    /// Id of request
+    pub id: RequestId,
+    /// URL of request, for check
+    pub url: String,
+    /// If present, request failed.
+    pub err: Option<String>,
+    /// Request was cancelled before it was started. "err" is also set.
+    pub cancelled: bool,
+    /// Request ran over its time limit, and the decoder is exiting. "err" is also set.
+    pub timed_out: bool,
+    /// Returned image discard level. 0 is full size, 1 halves each dimension, etc.
+    pub discard: u32,
+    /// Discard level the image actually has detail for. Higher than "discard"
+    /// if not all the data for "discard" was available, and the image is blurry.
+    /// None if not known, because the file's layout doesn't say and only part of it was read.
+    /// Not sent if None.
+    pub effective_discard: Option<u32>,
+    /// Returned image height
+    pub h: u32,
+    /// Returned image width
+    pub w: u32,
+    /// Returned image depth (3 for RGB, 4 for RGBA)
+    pub d: u8,
+    /// Returned image, raw bytes, no headers, size h * w * d
+    pub image: Vec<u8>,
+    /// Decoded image cache statistics, after this request. Sent, with or without "err",
+    /// as "cachehits", "cachemisses", "cacheimages" and "cachebytes", if present.
+    pub cache_stats: Option<StatsReply>,
+}
+
+impl DecodeReply {
+    /// Reply for a failed request.
+    pub fn error(id: RequestId, url: &str, err: String) -> DecodeReply {
+        DecodeReply {
+            id,
+            url: url.to_string(),
+            err: Some(err),
+            ..Default::default()
+        }
+    }
+
+    /// Reply for a request which ran out of time.
+    pub fn timed_out(id: RequestId, url: &str) -> DecodeReply {
+        DecodeReply {
+            timed_out: true,
+            ..DecodeReply::error(id, url, "Timeout".to_string())
+        }
+    }
+
+    /// Reply for a cancelled request.
+    pub fn cancelled(id: RequestId, url: &str) -> DecodeReply {
+        DecodeReply {
+            cancelled: true,
+            ..DecodeReply::error(id, url, "Cancelled".to_string())
+        }
+    }
+
+    /// Convert to LLSD for sending.
+    pub fn to_llsd(&self) -> HashMap<String, LLSDValue> {
+        let mut msg = HashMap::new();
+        msg.insert("id".to_string(), LLSDValue::Integer(self.id as i32));
+        msg.insert("url".to_string(), LLSDValue::String(self.url.clone()));
+        if let Some(stats) = &self.cache_stats {
+            let int = |v: u32| LLSDValue::Integer(v.min(i32::MAX as u32) as i32);
+            msg.insert("cachehits".to_string(), int(stats.hits));
+            msg.insert("cachemisses".to_string(), int(stats.misses));
+            msg.insert("cacheimages".to_string(), int(stats.images));
+            msg.insert("cachebytes".to_string(), int(stats.bytes));
+        }
+        if let Some(err) = &self.err {
+            msg.insert("err".to_string(), LLSDValue::String(err.clone()));
+            if self.cancelled {
+                msg.insert("cancelled".to_string(), LLSDValue::Boolean(true));
+            }
+            if self.timed_out {
+                msg.insert("timeout".to_string(), LLSDValue::Boolean(true));
+            }
+        } else {
+            msg.insert("discard".to_string(), LLSDValue::Integer(self.discard as i32));
+            if let Some(effective_discard) = self.effective_discard {
+                msg.insert("effectivediscard".to_string(), LLSDValue::Integer(effective_discard as i32));
+            }
+            msg.insert("h".to_string(), LLSDValue::Integer(self.h as i32));
+            msg.insert("w".to_string(), LLSDValue::Integer(self.w as i32));
+            msg.insert("d".to_string(), LLSDValue::Integer(self.d as i32));
+            msg.insert("image".to_string(), LLSDValue::Binary(self.image.clone()));
+        }
+        msg
+    }
+
+    /// Convert from received LLSD, with validation.
+    pub fn from_llsd(msg: &HashMap<String, LLSDValue>) -> Result<DecodeReply, Error> {
+        let id = get_u32(msg, "id")?.unwrap_or_default();
+        let url = get_string(msg, "url")?.unwrap_or_default();
+        let field = |key| get_u32(msg, key)?.ok_or_else(|| anyhow!("Reply has no \"{}\"", key));
+        let cache_stats = if msg.contains_key("cachehits") {
+            Some(StatsReply {
+                id,
+                hits: field("cachehits")?,
+                misses: field("cachemisses")?,
+                images: field("cacheimages")?,
+                bytes: field("cachebytes")?,
+            })
+        } else {
+            None
+        };
+        if let Some(err) = get_string(msg, "err")? {
+            let cancelled = matches!(msg.get("cancelled"), Some(LLSDValue::Boolean(true)));
+            let timed_out = matches!(msg.get("timeout"), Some(LLSDValue::Boolean(true)));
+            return Ok(DecodeReply {
+                cancelled,
+                timed_out,
+                cache_stats,
+                ..DecodeReply::error(id, &url, err)
+            });
+        }
+        let discard = field("discard")?;
+        let effective_discard = get_u32(msg, "effectivediscard")?;
+        let h = field("h")?;
+        let w = field("w")?;
+        let d = field("d")?;
+        let image = match msg.get("image") {
+            Some(LLSDValue::Binary(b)) => b.clone(),
+            _ => return Err(anyhow!("Reply has no binary \"image\"")),
+        };
+        if !(1..=4).contains(&d) {
+            return Err(anyhow!("Reply image depth {} out of range", d));
+        }
+        if (w as usize) * (h as usize) * (d as usize) != image.len() {
+            return Err(anyhow!(
+                "Reply image size {} does not match dimensions ({}, {}, {})",
+                image.len(),
+                w,
+                h,
+                d
+            ));
+        }
+        Ok(DecodeReply {
+            id,
+            url,
+            err: None,
+            cancelled: false,
+            timed_out: false,
+            discard,
+            effective_discard,
+            h,
+            w,
+            d: d as u8,
+            image,
+            cache_stats,
+        })
+    }
+}
+
+/// Reply to a "stats" command. Counts are since the decoder subprocess started.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsReply {
+    /// Id of request
+    pub id: RequestId,
+    /// URL requests answered from the decoded image cache.
+    pub hits: u32,
+    /// URL requests which had to be fetched and decoded.
+    pub misses: u32,
+    /// Images in the decoded image cache.
+    pub images: u32,
+    /// Bytes of pixels in the decoded image cache.
+    pub bytes: u32,
+}
+
+impl StatsReply {
+    /// Convert to LLSD for sending.
+    pub fn to_llsd(&self) -> HashMap<String, LLSDValue> {
+        let int = |v: u32| LLSDValue::Integer(v.min(i32::MAX as u32) as i32);
+        let mut msg = HashMap::new();
+        msg.insert("cmd".to_string(), LLSDValue::String("stats".to_string()));
+        msg.insert("id".to_string(), int(self.id));
+        msg.insert("hits".to_string(), int(self.hits));
+        msg.insert("misses".to_string(), int(self.misses));
+        msg.insert("images".to_string(), int(self.images));
+        msg.insert("bytes".to_string(), int(self.bytes));
+        msg
+    }
+
+    /// Convert from received LLSD, with validation.
+    pub fn from_llsd(msg: &HashMap<String, LLSDValue>) -> Result<StatsReply, Error> {
+        if get_string(msg, "cmd")?.as_deref() != Some("stats") {
+            return Err(anyhow!("Reply is not a \"stats\" reply"));
+        }
+        let field = |key| get_u32(msg, key)?.ok_or_else(|| anyhow!("Reply has no \"{}\"", key));
+        Ok(StatsReply {
+            id: get_u32(msg, "id")?.unwrap_or_default(),
+            hits: field("hits")?,
+            misses: field("misses")?,
+            images: field("images")?,
+            bytes: field("bytes")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_round_trip() {
+        let request = DecodeRequest {
+            id: 12,
+            priority: -3,
+            url: "http://www.example.com/file.j2k".to_string(),
+            data: None,
+            max_size: Some(999),
+            discard: None,
+            timeout_ms: Some(5000),
+            upgrade: false,
+        };
+        let reply = DecodeReply {
+            id: 12,
+            url: request.url.clone(),
+            err: None,
+            cancelled: false,
+            timed_out: false,
+            discard: 2,
+            effective_discard: Some(3),
+            h: 2,
+            w: 3,
+            d: 4,
+            image: vec![7; 2 * 3 * 4],
+            cache_stats: None,
+        };
+        let mut buf = Vec::new();
+        write_message(&mut buf, &request.to_llsd()).unwrap();
+        write_message(&mut buf, &reply.to_llsd()).unwrap();
+        let mut cursor = std::io::Cursor::new(buf);
+        let msg = read_message(&mut cursor).unwrap().unwrap();
+        assert_eq!(DecodeRequest::from_llsd(&msg).unwrap(), request);
+        let msg = read_message(&mut cursor).unwrap().unwrap();
+        assert_eq!(DecodeReply::from_llsd(&msg).unwrap(), reply);
+        assert!(read_message(&mut cursor).unwrap().is_none()); // clean EOF
+        //  Effective discard level not known
+        let reply = DecodeReply {
+            effective_discard: None,
+            ..reply
+        };
+        let msg = reply.to_llsd();
+        assert!(!msg.contains_key("effectivediscard"));
+        assert_eq!(DecodeReply::from_llsd(&msg).unwrap(), reply);
+        //  Cache statistics, on success and on error
+        let stats = StatsReply {
+            id: 12,
+            hits: 1,
+            misses: 2,
+            images: 1,
+            bytes: 24,
+        };
+        for reply in [reply.clone(), DecodeReply::error(12, &reply.url, "bad".to_string())] {
+            let reply = DecodeReply {
+                cache_stats: Some(stats.clone()),
+                ..reply
+            };
+            assert_eq!(DecodeReply::from_llsd(&reply.to_llsd()).unwrap(), reply);
+        }
+        let mut msg = DecodeReply {
+            cache_stats: Some(stats),
+            ..reply
+        }
+        .to_llsd();
+        msg.remove("cachebytes");
+        assert!(DecodeReply::from_llsd(&msg).is_err()); // all or none
+    }
+
+    #[test]
+    fn test_bad_request() {
+        let mut msg = DecodeRequest {
+            url: "http://www.example.com/file.j2k".to_string(),
+            max_size: Some(64),
+            discard: Some(1),
+            ..Default::default()
+        }
+        .to_llsd();
+        assert!(DecodeRequest::from_llsd(&msg).is_err()); // both maxsize and discard
+        msg.remove("url");
+        msg.remove("discard");
+        assert!(DecodeRequest::from_llsd(&msg).is_err()); // no URL
+        msg.insert("data".to_string(), LLSDValue::Binary(vec![0xff, 0x4f]));
+        assert!(DecodeRequest::from_llsd(&msg).is_ok()); // data instead of URL
+        msg.insert("url".to_string(), LLSDValue::String("http://www.example.com/file.j2k".to_string()));
+        assert!(DecodeRequest::from_llsd(&msg).is_err()); // both URL and data
+        //  Truncated message
+        let mut buf = Vec::new();
+        write_message(&mut buf, &DecodeReply::error(1, "x", "bad".to_string()).to_llsd()).unwrap();
+        buf.truncate(buf.len() - 1);
+        assert!(read_message(&mut std::io::Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        for cmd in [
+            Command::Cancel(5),
+            Command::SetPriority(6, -1),
+            Command::Stats(10),
+            Command::Decode(DecodeRequest {
+                id: 7,
+                url: "http://www.example.com/file.j2k".to_string(),
+                ..Default::default()
+            }),
+            Command::Decode(DecodeRequest {
+                id: 9,
+                url: "http://www.example.com/file.j2k".to_string(),
+                max_size: Some(512),
+                upgrade: true,
+                ..Default::default()
+            }),
+            Command::Decode(DecodeRequest {
+                id: 8,
+                data: Some(vec![0xff, 0x4f, 0xff, 0x51]),
+                ..Default::default()
+            }),
+        ] {
+            assert_eq!(Command::from_llsd(&cmd.to_llsd()).unwrap(), cmd);
+        }
+        for reply in [
+            DecodeReply::cancelled(5, "http://www.example.com/file.j2k"),
+            DecodeReply::timed_out(6, "http://www.example.com/file.j2k"),
+        ] {
+            assert_eq!(DecodeReply::from_llsd(&reply.to_llsd()).unwrap(), reply);
+        }
+        let stats = StatsReply {
+            id: 10,
+            hits: 3,
+            misses: 4,
+            images: 2,
+            bytes: 65536,
+        };
+        assert_eq!(StatsReply::from_llsd(&stats.to_llsd()).unwrap(), stats);
+        assert!(StatsReply::from_llsd(&DecodeReply::cancelled(5, "x").to_llsd()).is_err());
+        let mut msg = Command::Cancel(5).to_llsd();
+        msg.insert("cmd".to_string(), LLSDValue::String("explode".to_string()));
+        assert!(Command::from_llsd(&msg).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+//! # queue.rs  -- priority queue of decode requests.
+//
+//  Animats
+//  March, 2023
+//
+//  Used both by the pool in the library, and by the decoder in LLSD mode.
+//  Requests can be cancelled or reprioritized while waiting.
+//
+use crate::protocol::{DecodeRequest, RequestId};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+/// Queue position. Priority, then order of arrival.
+type QueueKey = (i32, Reverse<u64>);
+
+/// Requests waiting to be decoded.
+/// Highest priority first, then first come, first served.
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    /// Waiting requests.
+    queue: BTreeMap<QueueKey, DecodeRequest>,
+    /// Queue key of each waiting request, for cancel and reprioritize.
+    /// If an id is reused while waiting, only the latest request can be found.
+    keys: HashMap<RequestId, QueueKey>,
+    /// Order of arrival.
+    next_serial: u64,
+}
+
+impl RequestQueue {
+    /// Add a request.
+    pub fn push(&mut self, request: DecodeRequest) {
+        let key = (request.priority, Reverse(self.next_serial));
+        self.next_serial += 1;
+        self.keys.insert(request.id, key);
+        self.queue.insert(key, request);
+    }
+
+    /// Take the next request to be done.
+    pub fn pop(&mut self) -> Option<DecodeRequest> {
+        let (key, request) = self.queue.pop_last()?;
+        if self.keys.get(&request.id) == Some(&key) {
+            self.keys.remove(&request.id);
+        }
+        Some(request)
+    }
+
+    /// Is a request with this id waiting?
+    pub fn contains(&self, id: RequestId) -> bool {
+        self.keys.contains_key(&id)
+    }
+
+    /// Remove a waiting request. Returns it, or None if not waiting.
+    pub fn cancel(&mut self, id: RequestId) -> Option<DecodeRequest> {
+        let key = self.keys.remove(&id)?;
+        self.queue.remove(&key)
+    }
+
+    /// Change the priority of a waiting request. Returns false if not waiting.
+    pub fn set_priority(&mut self, id: RequestId, priority: i32) -> bool {
+        if let Some(mut request) = self.cancel(id) {
+            let key = (priority, Reverse(self.next_serial)); // goes to end of line at new priority
+            self.next_serial += 1;
+            request.priority = priority;
+            self.keys.insert(id, key);
+            self.queue.insert(key, request);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Number of waiting requests.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// True if nothing waiting.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_queue() {
+        let request = |id, priority| DecodeRequest {
+            id,
+            priority,
+            url: format!("http://www.example.com/{}.j2k", id),
+            ..Default::default()
+        };
+        let mut queue = RequestQueue::default();
+        queue.push(request(1, 0));
+        queue.push(request(2, 5));
+        queue.push(request(3, 0));
+        queue.push(request(4, 0));
+        queue.push(request(5, 0));
+        assert_eq!(queue.len(), 5);
+        assert!(queue.contains(4));
+        assert!(queue.cancel(4).is_some());
+        assert!(!queue.contains(4));
+        assert!(queue.cancel(4).is_none()); // already gone
+        assert!(queue.set_priority(3, 10));
+        assert!(!queue.set_priority(99, 10)); // never queued
+        let order: Vec<RequestId> = std::iter::from_fn(|| queue.pop().map(|r| r.id)).collect();
+        assert_eq!(order, vec![3, 2, 1, 5]);
+        assert!(queue.is_empty());
+    }
+}
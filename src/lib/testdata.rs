@@ -0,0 +1,83 @@
+//! # testdata.rs  -- small JPEG 2000 codestreams for tests.
+//!
+//  Animats
+//  March, 2023
+//
+//! Builds codestreams with a known layout, for tests of the header parser,
+//! the layout code, and the decoder executable's reads.
+//! Packet data is all zeros, so these do not decode to an image.
+//! Not part of the library interface.
+
+use crate::header::{COD, EOC, QCD, SIZ, SOC, SOT, TLM};
+use crate::layout::{PLT, SOD};
+
+/// Append a marker segment.
+pub fn marker(out: &mut Vec<u8>, marker: u16, contents: &[u8]) {
+    out.extend_from_slice(&marker.to_be_bytes());
+    out.extend_from_slice(&(contents.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(contents);
+}
+
+/// Main header of a 64 x 64 RGB codestream, one tile, 2 layers, 4 resolution levels,
+/// in the given progression order. Default precincts, so 6 packets per resolution level.
+/// A TLM marker is added if there are tile-part lengths.
+pub fn main_header(order: u8, tile_part_lengths: &[u32]) -> Vec<u8> {
+    let mut out = SOC.to_be_bytes().to_vec();
+    let mut siz = vec![0, 0];
+    for v in [64_u32, 64, 0, 0, 64, 64, 0, 0] {
+        siz.extend_from_slice(&v.to_be_bytes());
+    }
+    siz.extend_from_slice(&3_u16.to_be_bytes());
+    for _ in 0..3 {
+        siz.extend_from_slice(&[7, 1, 1]); // 8 bits unsigned, no subsampling
+    }
+    marker(&mut out, SIZ, &siz);
+    marker(&mut out, COD, &[0, order, 0, 2, 1, 3, 4, 4, 0, 0]);
+    marker(&mut out, QCD, &[0x42, 0x48, 0x50]);
+    if !tile_part_lengths.is_empty() {
+        let mut tlm = vec![0, 0x40]; // no tile index, 32 bit lengths
+        for length in tile_part_lengths {
+            tlm.extend_from_slice(&length.to_be_bytes());
+        }
+        marker(&mut out, TLM, &tlm);
+    }
+    out
+}
+
+/// Append a tile-part with the given packet lengths, and a PLT if requested.
+pub fn tile_part(out: &mut Vec<u8>, index: u8, count: u8, packets: &[usize], plt: bool) {
+    let mut header = Vec::new();
+    if plt {
+        let mut lengths = vec![0]; // Zplt
+        for length in packets {
+            if *length >= 128 {
+                lengths.push(0x80 | (length >> 7) as u8);
+            }
+            lengths.push((length & 0x7F) as u8);
+        }
+        marker(&mut header, PLT, &lengths);
+    }
+    let data_length: usize = packets.iter().sum();
+    let psot = 12 + header.len() + 2 + data_length;
+    let mut sot = vec![0, 0];
+    sot.extend_from_slice(&(psot as u32).to_be_bytes());
+    sot.extend_from_slice(&[index, count]);
+    marker(out, SOT, &sot);
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&SOD.to_be_bytes());
+    out.extend(std::iter::repeat_n(0_u8, data_length));
+}
+
+/// A whole codestream, from `main_header`, with each resolution level in its own tile-part,
+/// lowest first, and no TLM or PLT. Resolution level r has r + 1 packets of 100 bytes.
+/// Returns the codestream, and the end of each tile-part.
+pub fn codestream(order: u8) -> (Vec<u8>, Vec<usize>) {
+    let mut out = main_header(order, &[]);
+    let mut ends = Vec::new();
+    for r in 0..4_u8 {
+        tile_part(&mut out, r, 4, &vec![100; usize::from(r) + 1], false);
+        ends.push(out.len());
+    }
+    out.extend_from_slice(&EOC.to_be_bytes());
+    (out, ends)
+}